@@ -49,6 +49,19 @@ impl<'a> VirtualDeviceBuilder<'a> {
         })
     }
 
+    /// Start building on an already opened `/dev/uinput` file descriptor.
+    ///
+    /// This allows an unprivileged process to create virtual devices with a descriptor opened
+    /// and passed to it by a privileged one.
+    pub fn from_fd(fd: OwnedFd) -> Self {
+        VirtualDeviceBuilder {
+            fd,
+            name: Default::default(),
+            id: None,
+            ff_effects_max: 0,
+        }
+    }
+
     /// Set the display name of this device.
     #[inline]
     pub fn name<S: AsRef<[u8]> + ?Sized>(mut self, name: &'a S) -> Self {
@@ -0,0 +1,58 @@
+use evdev::KeyCode;
+use std::{sync::{Condvar, Mutex, MutexGuard, PoisonError}, time::Duration};
+
+// A combo asked for by `pindc bind`: every key pressed since it started, in order.
+// Done once they are all up again
+struct Capture
+{
+    pressed: Vec<KeyCode>,
+    held: Vec<KeyCode>,
+    done: bool,
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+static DONE: Condvar = Condvar::new();
+
+fn lock() -> MutexGuard<'static, Option<Capture>>
+{
+    CAPTURE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Wait for the next combo pressed on any keyboard, one capture at a time
+pub fn next(timeout: Duration) -> Result<Vec<KeyCode>, &'static str>
+{
+    let mut capture = lock();
+    if capture.is_some() { return Err("another combo is being captured"); }
+    *capture = Some(Capture { pressed: Vec::new(), held: Vec::new(), done: false });
+
+    let (mut capture, _) = DONE.wait_timeout_while(capture, timeout, |capture| capture.as_ref().is_some_and(|capture| !capture.done))
+        .unwrap_or_else(PoisonError::into_inner);
+    match capture.take() {
+        Some(capture) if capture.done => Ok(capture.pressed),
+        _ => Err("no combo was pressed in time"),
+    }
+}
+
+// Give a key event to the capture in progress, true if it takes it: then the event
+// neither runs a binding nor reaches the compositor
+pub fn feed(key: KeyCode, value: i32) -> bool
+{
+    let mut capture = lock();
+    let Some(capture) = capture.as_mut().filter(|capture| !capture.done) else { return false };
+    match value {
+        1 => {
+            if !capture.pressed.contains(&key) { capture.pressed.push(key); }
+            if !capture.held.contains(&key) { capture.held.push(key); }
+        }
+        0 => {
+            capture.held.retain(|&k| k != key);
+            // A key held before the capture started isn't part of the combo
+            if capture.held.is_empty() && !capture.pressed.is_empty() {
+                capture.done = true;
+                DONE.notify_all();
+            }
+        }
+        _ => {}
+    }
+    true
+}
@@ -0,0 +1,647 @@
+use evdev::{AttributeSet, KeyCode};
+use std::{fs::{File, Metadata, OpenOptions, metadata, symlink_metadata, read_to_string, canonicalize}, io::Read, os::unix::fs::{MetadataExt, OpenOptionsExt}, path::{Path, PathBuf}, time::Duration};
+use crate::{environ, user::{self, User}};
+
+const ALLOW_ROOT: &str = "/etc/pind/allow-root"; // configs, one path per line, allowed to use `@as`
+const CONFIG    : &str = "pind/pindrc"; // in $XDG_CONFIG_HOME, ~/.config by default
+
+pub struct Config
+{
+    pub path: PathBuf, // canonical, reloads read it again
+    pub bindings: Vec<Binding>,
+    pub listen_only: bool, // observe the keyboards without grabbing them or using uinput
+    pub escape: AttributeSet<KeyCode>, // held for `escape_hold`, releases every keyboard and exits
+    pub escape_hold: Duration,
+    pub timeout: Option<Duration>, // for bindings without their own `@timeout`
+    pub shell: Option<PathBuf>, // for bindings without their own `@shell`, the login shell otherwise
+}
+
+#[derive(Clone)]
+pub struct Binding
+{
+    pub combo: String, // as written in pindrc
+    pub keys: AttributeSet<KeyCode>,
+    pub command: String,
+    pub passthrough: bool, // `~`: the keys still reach the focused application
+    pub policy: Policy,
+    pub log: bool, // output goes to the command log, `@nolog` drops it
+    pub timeout: Option<Duration>, // the command's process group is killed past it
+    pub shell: Option<PathBuf>, // runs `command` with -c, the login shell if None
+    pub argv: Option<Vec<String>>, // `@exec`: run directly, no shell involved
+    pub run_as: Option<User>, // `@as`: someone else than the daemon's user, see `may_run_as`
+}
+
+impl Binding
+{
+    // Whether a reloaded binding still runs the same thing the same way
+    fn same(&self, other: &Binding) -> bool
+    {
+        self.keys == other.keys && self.command == other.command && self.passthrough == other.passthrough
+            && self.policy == other.policy && self.log == other.log && self.timeout == other.timeout
+            && self.shell == other.shell && self.argv == other.argv
+            && self.run_as.as_ref().map(|user| user.uid) == other.run_as.as_ref().map(|user| user.uid)
+    }
+}
+
+// The id in `new` of every binding of `old`, None for the ones removed or changed
+pub fn renumber(old: &[Binding], new: &[Binding]) -> Vec<Option<usize>>
+{
+    old.iter().map(|binding| new.iter().position(|new| new.same(binding))).collect()
+}
+
+// What a press does while the binding's previous command is still running
+#[derive(Clone, Copy, PartialEq)]
+pub enum Policy
+{
+    Parallel, // start another one
+    Single,   // ignore the press
+    Queue,    // start it once the previous ones are done
+    Restart,  // kill the previous one first
+}
+
+const MODIFIERS: [KeyCode; 8] = [
+    KeyCode::KEY_LEFTCTRL, KeyCode::KEY_RIGHTCTRL, KeyCode::KEY_LEFTSHIFT, KeyCode::KEY_RIGHTSHIFT,
+    KeyCode::KEY_LEFTALT, KeyCode::KEY_RIGHTALT, KeyCode::KEY_LEFTMETA, KeyCode::KEY_RIGHTMETA,
+];
+
+// Names of the keys in pindrc, the first one is how pind writes a key
+const KEYS: &[(&[&str], KeyCode)] = &[
+    (&["meta", "meta_left"], KeyCode::KEY_LEFTMETA),
+    (&["ctrl", "ctrl_left"], KeyCode::KEY_LEFTCTRL),
+    (&["shift", "shift_left"], KeyCode::KEY_LEFTSHIFT),
+    (&["alt", "alt_left"], KeyCode::KEY_LEFTALT),
+    (&["back_slash", "\\"], KeyCode::KEY_BACKSLASH),
+    (&["slash", "/"], KeyCode::KEY_SLASH),
+    (&["enter", "return"], KeyCode::KEY_ENTER),
+    (&["ctrl_right"], KeyCode::KEY_RIGHTCTRL),
+    (&["shift_right"], KeyCode::KEY_RIGHTSHIFT),
+    (&["meta_right"], KeyCode::KEY_RIGHTMETA),
+    (&["alt_right"], KeyCode::KEY_RIGHTALT),
+    (&["capslock"], KeyCode::KEY_CAPSLOCK),
+    (&["tab"], KeyCode::KEY_TAB),
+    (&["backspace"], KeyCode::KEY_BACKSPACE),
+    (&["print", "sysrq"], KeyCode::KEY_SYSRQ),
+    (&["0"], KeyCode::KEY_0),
+    (&["1"], KeyCode::KEY_1),
+    (&["2"], KeyCode::KEY_2),
+    (&["3"], KeyCode::KEY_3),
+    (&["4"], KeyCode::KEY_4),
+    (&["5"], KeyCode::KEY_5),
+    (&["6"], KeyCode::KEY_6),
+    (&["7"], KeyCode::KEY_7),
+    (&["8"], KeyCode::KEY_8),
+    (&["9"], KeyCode::KEY_9),
+    (&["a"], KeyCode::KEY_A),
+    (&["b"], KeyCode::KEY_B),
+    (&["c"], KeyCode::KEY_C),
+    (&["d"], KeyCode::KEY_D),
+    (&["e"], KeyCode::KEY_E),
+    (&["f"], KeyCode::KEY_F),
+    (&["g"], KeyCode::KEY_G),
+    (&["h"], KeyCode::KEY_H),
+    (&["i"], KeyCode::KEY_I),
+    (&["j"], KeyCode::KEY_J),
+    (&["k"], KeyCode::KEY_K),
+    (&["l"], KeyCode::KEY_L),
+    (&["m"], KeyCode::KEY_M),
+    (&["n"], KeyCode::KEY_N),
+    (&["o"], KeyCode::KEY_O),
+    (&["p"], KeyCode::KEY_P),
+    (&["q"], KeyCode::KEY_Q),
+    (&["r"], KeyCode::KEY_R),
+    (&["s"], KeyCode::KEY_S),
+    (&["t"], KeyCode::KEY_T),
+    (&["u"], KeyCode::KEY_U),
+    (&["v"], KeyCode::KEY_V),
+    (&["w"], KeyCode::KEY_W),
+    (&["x"], KeyCode::KEY_X),
+    (&["y"], KeyCode::KEY_Y),
+    (&["z"], KeyCode::KEY_Z),
+    (&["up"], KeyCode::KEY_UP),
+    (&["down"], KeyCode::KEY_DOWN),
+    (&["right"], KeyCode::KEY_RIGHT),
+    (&["left"], KeyCode::KEY_LEFT),
+    (&["dot", "."], KeyCode::KEY_DOT),
+    (&["comma", ","], KeyCode::KEY_COMMA),
+    (&["semicolon", ";"], KeyCode::KEY_SEMICOLON),
+    (&["apostrophe", "\""], KeyCode::KEY_APOSTROPHE),
+    (&["leftbrace", "["], KeyCode::KEY_LEFTBRACE),
+    (&["rightbrace", "]"], KeyCode::KEY_RIGHTBRACE),
+    (&["numlock"], KeyCode::KEY_NUMLOCK),
+    (&["scroll_lock"], KeyCode::KEY_SCROLLLOCK),
+    (&["minus", "-"], KeyCode::KEY_MINUS),
+    (&["equal", "="], KeyCode::KEY_EQUAL),
+    (&["kpplus", "plus", "keypad_plus"], KeyCode::KEY_KPPLUS),
+    (&["grave", "`"], KeyCode::KEY_GRAVE),
+    (&["space"], KeyCode::KEY_SPACE),
+    (&["esc"], KeyCode::KEY_ESC),
+    (&["f1"], KeyCode::KEY_F1),
+    (&["f2"], KeyCode::KEY_F2),
+    (&["f3"], KeyCode::KEY_F3),
+    (&["f4"], KeyCode::KEY_F4),
+    (&["f5"], KeyCode::KEY_F5),
+    (&["f6"], KeyCode::KEY_F6),
+    (&["f7"], KeyCode::KEY_F7),
+    (&["f8"], KeyCode::KEY_F8),
+    (&["f9"], KeyCode::KEY_F9),
+    (&["f10"], KeyCode::KEY_F10),
+    (&["f11"], KeyCode::KEY_F11),
+    (&["f12"], KeyCode::KEY_F12),
+    (&["kp0", "keypad_0"], KeyCode::KEY_KP0),
+    (&["kp1", "keypad_1"], KeyCode::KEY_KP1),
+    (&["kp2", "keypad_2"], KeyCode::KEY_KP2),
+    (&["kp3", "keypad_3"], KeyCode::KEY_KP3),
+    (&["kp4", "keypad_4"], KeyCode::KEY_KP4),
+    (&["kp5", "keypad_5"], KeyCode::KEY_KP5),
+    (&["kp6", "keypad_6"], KeyCode::KEY_KP6),
+    (&["kp7", "keypad_7"], KeyCode::KEY_KP7),
+    (&["kp8", "keypad_8"], KeyCode::KEY_KP8),
+    (&["kp9", "keypad_9"], KeyCode::KEY_KP9),
+    (&["kpdot", "keypad_dot"], KeyCode::KEY_KPDOT),
+    (&["kpminus", "keypad_minus"], KeyCode::KEY_KPMINUS),
+];
+
+pub fn key_to_keycode(input: &str) -> Result<AttributeSet<KeyCode>, String>
+{
+    let mut attribute_set = AttributeSet::new();
+
+    for key in input.replace(' ',"").split('+')  {
+        let key = key.to_lowercase();
+        match KEYS.iter().find(|(names, _)| names.contains(&key.as_str())) {
+            Some((_, code)) => attribute_set.insert(*code),
+            None => return Err(format!("key {} is not exists",key)),
+        }
+    }
+    Ok(attribute_set)
+}
+
+// The name pind writes for a key, None for keys pindrc can't name
+pub fn key_name(code: KeyCode) -> Option<&'static str>
+{
+    KEYS.iter().find(|(_, key)| *key == code).map(|(names, _)| names[0])
+}
+
+// Keys written as a combo of pindrc: modifiers first, in this order, then the others as given.
+// A key without a name is returned instead
+pub fn combo(keys: &[KeyCode]) -> Result<String, KeyCode>
+{
+    let modifiers = MODIFIERS.iter().filter(|modifier| keys.contains(modifier));
+    let others = keys.iter().filter(|key| !MODIFIERS.contains(key));
+    let names = modifiers.chain(others).map(|&key| key_name(key).ok_or(key)).collect::<Result<Vec<_>, _>>()?;
+    Ok(names.join(" + "))
+}
+
+// An explicit path (--config), $PIND_CONFIG, $XDG_CONFIG_HOME/pind/pindrc if it exists, then ~/.config/pind/pindrc.
+// Home is the user's from passwd, the daemon's $HOME is usually root's
+pub fn resolve(explicit: Option<PathBuf>, user: &User) -> PathBuf
+{
+    let expand = |path: PathBuf| match path.strip_prefix("~") {
+        Ok(rest) => user.home.join(rest),
+        Err(_) => path,
+    };
+    if let Some(path) = explicit.or_else(|| environ::var("PIND_CONFIG").filter(|path| !path.is_empty()).map(PathBuf::from)) {
+        return expand(path);
+    }
+    environ::var("XDG_CONFIG_HOME").map(PathBuf::from).filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join(CONFIG))
+        .filter(|path| path.exists())
+        .unwrap_or_else(|| user.home.join(".config").join(CONFIG))
+}
+
+// A symlink on the way to the config, as given, is only followed if root made it, or the user
+// (`uid`) made it to something of theirs. Otherwise a link to a file of root or someone else
+// would have it read, and shown in the errors, for the user
+fn links(config: &Path, uid: u32) -> Result<(), String>
+{
+    let config = std::path::absolute(config).map_err(|e| format!("{}: {e}", config.display()))?;
+    let mut path = PathBuf::new();
+    for component in config.components() {
+        path.push(component);
+        let link = symlink_metadata(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        if !link.is_symlink() || link.uid() == 0 { continue; }
+        let target = metadata(&path).map_err(|e| format!("{}: {e}", path.display()))?;
+        if link.uid() != uid || target.uid() != uid {
+            return Err(format!("{} is a symlink to something the user doesn't own", path.display()));
+        }
+    }
+    Ok(())
+}
+
+// The file at `path`, if it is still the one `checked` was taken of
+fn opened(path: &Path, checked: &Metadata) -> Result<(File, Metadata), String>
+{
+    let file = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC).open(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    let opened = file.metadata().map_err(|e| format!("{}: {e}", path.display()))?;
+    if (opened.dev(), opened.ino()) != (checked.dev(), checked.ino()) {
+        return Err(format!("{} was replaced while being checked", path.display()));
+    }
+    Ok((file, opened))
+}
+
+// Root runs what the config says, so it must only be writable by the user (`uid`) or root.
+// `path` is canonical, once `links` allowed the way there: the file and every directory
+// above it are checked, then it is opened without following links and compared to what was checked.
+// What is said about the file afterwards comes from the opened one, not from the path again
+fn read_config(path: &Path, uid: u32) -> Result<(String, Metadata), String>
+{
+    let trusted = |path: &Path, meta: &Metadata| {
+        if meta.uid() != uid && meta.uid() != 0 {
+            return Err(format!("{} is owned by uid {}, neither the user nor root", path.display(), meta.uid()));
+        }
+        // Nobody can replace what others own in a sticky directory like /tmp
+        if meta.mode() & 0o022 != 0 && !(meta.is_dir() && meta.mode() & 0o1000 != 0) {
+            return Err(format!("{} is writable by group or others, fix it with `chmod go-w {}`", path.display(), path.display()));
+        }
+        Ok(())
+    };
+
+    for dir in path.ancestors().skip(1) {
+        trusted(dir, &metadata(dir).map_err(|e| format!("{}: {e}", dir.display()))?)?;
+    }
+    let checked = metadata(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if !checked.is_file() { return Err(format!("{} is not a regular file", path.display())); }
+    trusted(path, &checked)?;
+
+    let (mut file, opened) = opened(path, &checked)?;
+    let mut content = String::new();
+    file.read_to_string(&mut content).map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok((content, opened))
+}
+
+// The first problem found is returned, nothing of a config that doesn't load is used:
+// a running pindd keeps the one it has
+pub fn load_config(config: &Path, user: &User) -> Result<Config, String>
+{
+    links(config, user.uid).map_err(|e| format!("refused, {e}"))?;
+    let path = canonicalize(config).map_err(|e| format!("{}: {e}", config.display()))?;
+    let (content, file) = read_config(&path, user.uid).map_err(|e| format!("refused, {e}"))?;
+    // The root helper reads it too, never what the user couldn't
+    let readable = file.uid() == user.uid || file.mode() & 0o004 != 0
+        || (file.mode() & 0o040 != 0 && user.groups().contains(&file.gid()));
+    if !readable { return Err(format!("refused, {} is not readable by {}", path.display(), user.name)); }
+
+    let mut config = Config {
+        path: path.clone(),
+        bindings: Vec::new(),
+        listen_only: false,
+        escape: AttributeSet::from_iter([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_LEFTALT, KeyCode::KEY_BACKSPACE, KeyCode::KEY_ESC]),
+        escape_hold: Duration::from_secs(2),
+        timeout: None,
+        shell: None,
+    };
+    for line in content.lines().map(str::trim).filter(|line| !line.starts_with('#') && !line.is_empty()) {
+        if let Some((key, cmd)) = line.split_once("=>") {
+            config.bindings.push(parse_binding(key, cmd)?);
+        } else if let Some((name, value)) = line.split_once('=') {
+            set_option(&mut config, name.trim(), value.trim())?;
+        }
+    }
+
+    // A timeout of 0 means none
+    for binding in &mut config.bindings {
+        binding.timeout = binding.timeout.or(config.timeout).filter(|timeout| !timeout.is_zero());
+        binding.shell = binding.shell.take().or_else(|| config.shell.clone());
+    }
+
+    if let Some(binding) = config.bindings.iter().find(|b| b.run_as.is_some()) && !may_run_as(&file) {
+        return Err(format!("`{}` uses @as, but {} is not owned by root nor listed in {ALLOW_ROOT}", binding.combo, path.display()));
+    }
+    Ok(config)
+}
+
+// Running commands as root or another user is granted by whoever owns the config, so only a
+// root owned config or one root listed in ALLOW_ROOT (itself root owned and only writable by root) may
+fn may_run_as(config: &Metadata) -> bool
+{
+    if config.uid() == 0 { return true; }
+    let allow = Path::new(ALLOW_ROOT);
+    let trusted = [allow, allow.parent().unwrap_or(allow)].iter()
+        .all(|path| metadata(path).is_ok_and(|meta| meta.uid() == 0 && meta.mode() & 0o022 == 0));
+    trusted && read_to_string(ALLOW_ROOT).is_ok_and(|allowed| {
+        allowed.lines().map(str::trim).filter(|line| !line.starts_with('#'))
+            .any(|line| metadata(line).is_ok_and(|line| (line.dev(), line.ino()) == (config.dev(), config.ino())))
+    })
+}
+
+// `[~]combo => [@option]... command`
+fn parse_binding(key: &str, cmd: &str) -> Result<Binding, String>
+{
+    let key = key.trim();
+    let (passthrough, key) = match key.strip_prefix('~') {
+        Some(key) => (true, key.trim()),
+        None      => (false, key),
+    };
+    let mut binding = Binding {
+        combo: key.to_string(),
+        keys: key_to_keycode(key)?,
+        command: String::new(),
+        passthrough,
+        policy: Policy::Parallel,
+        log: true,
+        timeout: None,
+        shell: None,
+        argv: None,
+        run_as: None,
+    };
+
+    let mut cmd = cmd.trim();
+    while let Some(rest) = cmd.strip_prefix('@') {
+        let (option, rest) = word(rest);
+        cmd = rest;
+        match option {
+            "parallel" => binding.policy = Policy::Parallel,
+            "single"   => binding.policy = Policy::Single,
+            "queue"    => binding.policy = Policy::Queue,
+            "restart"  => binding.policy = Policy::Restart,
+            "nolog"    => binding.log = false,
+            "timeout"  => {
+                let (value, rest) = word(cmd);
+                binding.timeout = Some(duration("@timeout", value)?);
+                cmd = rest;
+            }
+            "shell"    => {
+                let (value, rest) = word(cmd);
+                binding.shell = Some(PathBuf::from(value));
+                cmd = rest;
+            }
+            "as"       => {
+                let (value, rest) = word(cmd);
+                binding.run_as = Some(user::lookup(value).ok_or_else(|| format!("@as of `{key}`: user {value} is not exists"))?);
+                cmd = rest;
+            }
+            // The rest of the line is the command, split like a shell would but never run by one
+            "exec"     => {
+                let argv = split_argv(cmd).map_err(|e| format!("@exec of `{key}` {e}"))?;
+                if argv.is_empty() { return Err(format!("@exec of `{key}` needs a command")); }
+                binding.argv = Some(argv);
+                break;
+            }
+            _ => return Err(format!("binding option @{option} is not exists")),
+        }
+    }
+    binding.command = cmd.to_string();
+    Ok(binding)
+}
+
+// Words separated by blanks, with '...' taken literally, "..." where \ only escapes
+// \ " $ and `, and \ outside of quotes escaping anything. No expansion happens
+fn split_argv(text: &str) -> Result<Vec<String>, String>
+{
+    let mut argv = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => argv.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err("has an unterminated '".into()),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('\\' | '"' | '$' | '`')) => arg.push(c),
+                            Some(c) => { arg.push('\\'); arg.push(c); }
+                            None => return Err("has an unterminated \"".into()),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err("has an unterminated \"".into()),
+                    }
+                }
+            }
+            '\\' => arg.get_or_insert_default().push(chars.next().ok_or("ends with \\")?),
+            c => arg.get_or_insert_default().push(c),
+        }
+    }
+    argv.extend(arg);
+    Ok(argv)
+}
+
+// The first word and what comes after it
+fn word(text: &str) -> (&str, &str)
+{
+    let text = text.trim_start();
+    let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    (word, rest.trim_start())
+}
+
+fn set_option(config: &mut Config, name: &str, value: &str) -> Result<(), String>
+{
+    match name {
+        "listen_only" => config.listen_only = flag(name, value)?,
+        "escape"      => config.escape = key_to_keycode(value)?,
+        "escape_hold" => config.escape_hold = duration(name, value)?,
+        "timeout"     => config.timeout = Some(duration(name, value)?),
+        "shell"       => config.shell = Some(PathBuf::from(value)),
+        _ => return Err(format!("option {name} is not exists")),
+    }
+    Ok(())
+}
+
+fn flag(name: &str, value: &str) -> Result<bool, String>
+{
+    match value.to_lowercase().as_str() {
+        "true"  | "yes" | "on"  => Ok(true),
+        "false" | "no"  | "off" => Ok(false),
+        _ => Err(format!("option {name} expects true or false, not {value}")),
+    }
+}
+
+// `500ms`, `2s`, `1m`, plain numbers are seconds. Too long to be a Duration is an error too
+fn duration(name: &str, value: &str) -> Result<Duration, String>
+{
+    let value = value.to_lowercase();
+    let parsed = if let Some(ms) = value.strip_suffix("ms") {
+        ms.trim().parse().ok().map(Duration::from_millis)
+    } else if let Some(m) = value.strip_suffix('m') {
+        m.trim().parse().ok().and_then(|m: u64| m.checked_mul(60)).map(Duration::from_secs)
+    } else {
+        value.trim_end_matches('s').trim().parse().ok().and_then(|s: f64| Duration::try_from_secs_f64(s).ok())
+    };
+    parsed.ok_or_else(|| format!("option {name} expects a duration like 500ms or 2s, not {value}"))
+}
+
+// Things that don't stop the daemon but mean a binding won't behave as written
+pub fn check(config: &Config) -> Vec<String>
+{
+    let mut problems = Vec::new();
+    for binding in config.bindings.iter().filter(|b| config.escape.iter().all(|k| b.keys.contains(k))) {
+        problems.push(format!("`{}` contains the escape combo, holding it releases every keyboard", binding.combo));
+    }
+    if config.listen_only {
+        for binding in config.bindings.iter().filter(|b| !b.passthrough) {
+            problems.push(format!("`{}` suppresses its keys, which is unsupported in listen-only mode (prefix it with ~)", binding.combo));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{Policy, duration, links, opened, parse_binding, read_config, split_argv};
+    use std::{env::temp_dir, fs::{self, Permissions}, os::unix::fs::{PermissionsExt, chown, lchown, symlink}, path::PathBuf, process, time::Duration};
+
+    fn argv(text: &str) -> Vec<String>
+    {
+        split_argv(text).unwrap()
+    }
+
+    // An empty directory of its own in the temp dir, 0755
+    fn scratch(name: &str) -> PathBuf
+    {
+        let dir = temp_dir().join(format!("pind-test-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    fn uid() -> u32
+    {
+        unsafe { libc::geteuid() }
+    }
+
+    #[test]
+    fn single_quotes_are_literal()
+    {
+        assert_eq!(argv(r#"echo 'a  b' '$HOME \n "x"'"#), ["echo", "a  b", r#"$HOME \n "x""#]);
+    }
+
+    #[test]
+    fn double_quotes_only_escape_some()
+    {
+        assert_eq!(argv(r#"echo "\$HOME" "\n" "\\ \" \`""#), ["echo", "$HOME", r"\n", r#"\ " `"#]);
+    }
+
+    #[test]
+    fn backslash_outside_quotes()
+    {
+        assert_eq!(argv(r"echo a\ b \'"), ["echo", "a b", "'"]);
+        assert!(split_argv(r"echo a\").is_err());
+    }
+
+    #[test]
+    fn unterminated_quotes()
+    {
+        assert!(split_argv("echo 'a").is_err());
+        assert!(split_argv(r#"echo "a"#).is_err());
+        assert!(split_argv(r#"echo "a\"#).is_err());
+    }
+
+    #[test]
+    fn empty_arguments_are_kept()
+    {
+        assert_eq!(argv(r#"printf '' "" x"#), ["printf", "", "", "x"]);
+        assert_eq!(argv("  a   b  "), ["a", "b"]);
+    }
+
+    #[test]
+    fn options_before_exec()
+    {
+        let binding = parse_binding("meta + a", " @timeout 2s @single @exec notify-send 'a b' ").unwrap();
+        assert_eq!(binding.timeout, Some(Duration::from_secs(2)));
+        assert!(binding.policy == Policy::Single);
+        assert_eq!(binding.argv.unwrap(), ["notify-send", "a b"]);
+
+        // Everything after @exec is the command
+        let binding = parse_binding("meta + a", "@exec echo @timeout 2s").unwrap();
+        assert_eq!(binding.timeout, None);
+        assert_eq!(binding.argv.unwrap(), ["echo", "@timeout", "2s"]);
+    }
+
+    #[test]
+    fn bad_bindings()
+    {
+        assert!(parse_binding("meta + a", "@exec").is_err());
+        assert!(parse_binding("meta + a", "@exec 'x").is_err());
+        assert!(parse_binding("meta + a", "@timeout soon x").is_err());
+        assert!(parse_binding("meta + a", "@bogus x").is_err());
+        assert!(parse_binding("meta + nokey", "x").is_err());
+    }
+
+    #[test]
+    fn durations()
+    {
+        assert_eq!(duration("t", "500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(duration("t", "2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(duration("t", "1.5"), Ok(Duration::from_millis(1500)));
+        assert_eq!(duration("t", "1m"), Ok(Duration::from_secs(60)));
+        for value in ["-1", "nan", "inf", "1e20", "999999999999999999m", "soon"] {
+            assert!(duration("t", value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn writable_by_others()
+    {
+        let dir = scratch("writable");
+        let config = dir.join("pindrc");
+        fs::write(&config, "meta + f1 => true\n").unwrap();
+        for mode in [0o600, 0o644] {
+            fs::set_permissions(&config, Permissions::from_mode(mode)).unwrap();
+            assert!(read_config(&config, uid()).is_ok(), "{mode:o}");
+        }
+        for mode in [0o620, 0o602, 0o666] {
+            fs::set_permissions(&config, Permissions::from_mode(mode)).unwrap();
+            assert!(read_config(&config, uid()).is_err(), "{mode:o}");
+        }
+
+        // Others may add files to a sticky directory, not replace the user's
+        fs::set_permissions(&config, Permissions::from_mode(0o644)).unwrap();
+        fs::set_permissions(&dir, Permissions::from_mode(0o1777)).unwrap();
+        assert!(read_config(&config, uid()).is_ok());
+        fs::set_permissions(&dir, Permissions::from_mode(0o777)).unwrap();
+        assert!(read_config(&config, uid()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks_to_others()
+    {
+        let dir = scratch("symlinks");
+        let mine = dir.join("mine");
+        fs::write(&mine, "").unwrap();
+        let (to_mine, to_root) = (dir.join("to-mine"), dir.join("to-root"));
+        symlink(&mine, &to_mine).unwrap();
+        symlink("/etc/passwd", &to_root).unwrap();
+
+        // Links root made are followed, so as root the links and the file go to someone else
+        let uid = if uid() == 0 {
+            for path in [&to_mine, &to_root] { lchown(path, Some(4242), None).unwrap(); }
+            chown(&mine, Some(4242), None).unwrap();
+            4242
+        } else {
+            uid()
+        };
+        assert!(links(&to_mine, uid).is_ok());
+        assert!(links(&to_root, uid).is_err());
+        assert!(links(&dir.join("to-root/../mine"), uid).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn swapped_while_checked()
+    {
+        let dir = scratch("swapped");
+        let (config, other) = (dir.join("pindrc"), dir.join("other"));
+        fs::write(&config, "meta + f1 => true\n").unwrap();
+        fs::write(&other, "meta + f1 => @as root true\n").unwrap();
+        let checked = fs::metadata(&config).unwrap();
+        assert!(opened(&config, &checked).is_ok());
+
+        fs::rename(&other, &config).unwrap();
+        assert!(opened(&config, &checked).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{error, lock::{RUN, run_dir}, user::User};
+use std::{fs, io::{self, Read, Write}, os::unix::{fs::{PermissionsExt, chown}, net::{UnixListener, UnixStream}}, path::{Path, PathBuf}, time::Duration};
+
+const TIMEOUT :Duration = Duration::from_secs(5); // for pindd to answer a request
+pub const CAPTURE :Duration = Duration::from_secs(10); // for a combo to be pressed, see `capture`
+
+// <user>.sock next to the pidfile, pindc asks the user's pindd for its status or a reload through it.
+// pindc runs as the user, it looks in /run/pind first for a pindd started as root
+pub fn socket(user: &str) -> PathBuf
+{
+    let name = format!("{user}.sock");
+    let root = Path::new(RUN).join(&name);
+    if root.exists() { root } else { run_dir().join(name) }
+}
+
+// Created next to the pidfile and given to the user, so only the user and root can connect.
+// Call it with the lock held, a socket left behind by a previous instance is replaced
+pub fn listen(user: &User) -> UnixListener
+{
+    let path = run_dir().join(format!("{}.sock", user.name));
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap_or_else(|e| error("Control", &format!("Failed to listen on {}: {e}", path.display())));
+    let owned = chown(&path, Some(user.uid), Some(user.gid)).and_then(|_| fs::set_permissions(&path, fs::Permissions::from_mode(0o600)));
+    if let Err(e) = owned { error("Control", &format!("Failed to restrict {}: {e}", path.display())); }
+    listener
+}
+
+// Send one request to the user's pindd and return its answer
+pub fn request(user: &str, request: &str) -> io::Result<String>
+{
+    send(user, request, TIMEOUT)
+}
+
+// The next combo pressed on the keyboards of the user's pindd, written like in pindrc.
+// pindd swallows it and only answers once every key is up again, or after CAPTURE
+pub fn capture(user: &str) -> io::Result<String>
+{
+    send(user, "capture", CAPTURE + TIMEOUT)
+}
+
+fn send(user: &str, request: &str, timeout: Duration) -> io::Result<String>
+{
+    let mut stream = UnixStream::connect(socket(user))?;
+    stream.set_read_timeout(Some(timeout))?;
+    writeln!(stream, "{request}")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    Ok(answer)
+}
@@ -0,0 +1,67 @@
+use crate::verdict;
+use evdev::{BusType, EventType, enumerate};
+use serde::Serialize;
+use std::path::PathBuf;
+
+// What `pindd --list-devices` shows of an event device
+#[derive(Serialize)]
+struct Info
+{
+    path: PathBuf,
+    name: Option<String>,
+    bus: BusType,
+    vendor: u16,
+    product: u16,
+    version: u16,
+    phys: Option<String>,
+    uniq: Option<String>,
+    events: Vec<EventType>,
+    keys: usize,
+    grab: bool,
+    reason: &'static str, // why it is grabbed or not
+}
+
+// Every event device this process can open and what pindd would do with it, nothing is grabbed
+pub fn list(json: bool)
+{
+    let mut devices: Vec<_> = enumerate().map(|(path, device)| {
+        let id = device.input_id();
+        let (grab, reason) = verdict(&device);
+        Info {
+            path,
+            name: device.name().map(str::to_string),
+            bus: id.bus_type(),
+            vendor: id.vendor(),
+            product: id.product(),
+            version: id.version(),
+            phys: device.physical_path().map(str::to_string),
+            uniq: device.unique_name().filter(|uniq| !uniq.is_empty()).map(str::to_string),
+            events: device.supported_events().iter().collect(),
+            keys: device.supported_keys().map_or(0, |keys| keys.iter().count()),
+            grab,
+            reason,
+        }
+    })
+    .collect();
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if json {
+        match serde_json::to_string_pretty(&devices) {
+            Ok(json) => println!("{json}"),
+            Err(e) => eprintln!("Failed to serialize the devices: {e}"),
+        }
+        return;
+    }
+    if devices.is_empty() { eprintln!("No input device could be opened"); }
+    if unsafe { libc::geteuid() } != 0 { eprintln!("Only the devices this user can open are listed, run it as root to see them all"); }
+    for info in devices {
+        let events: Vec<_> = info.events.iter().map(|event| format!("{event:?}")).collect();
+        println!("{}  {}", info.path.display(), info.name.as_deref().unwrap_or("unnamed"));
+        println!("    id      bus {:?}, vendor {:04x}, product {:04x}, version {:04x}", info.bus, info.vendor, info.product, info.version);
+        println!("    phys    {}", info.phys.as_deref().unwrap_or("-"));
+        println!("    uniq    {}", info.uniq.as_deref().unwrap_or("-"));
+        println!("    events  {}", events.join(", "));
+        println!("    keys    {}", info.keys);
+        println!("    grab    {}, {}", if info.grab { "yes" } else { "no" }, info.reason);
+    }
+}
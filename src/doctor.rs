@@ -0,0 +1,145 @@
+use crate::{config::{self, check, load_config}, environ, is_keyboard, user::{self, User}, verdict};
+use evdev::enumerate;
+use std::{ffi::CString, fs::{self, OpenOptions}, io::ErrorKind, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, process::exit};
+
+const UINPUT :&str = "/dev/uinput";
+const INPUT  :&str = "/dev/input";
+const GROUP  :&str = "input"; // owns /dev/input/event* on most distributions
+
+// Counts the failed checks, every check prints one line and a hint when it fails
+struct Doctor(u32);
+
+impl Doctor
+{
+    fn pass(&self, message: &str)
+    {
+        println!("[\x1b[32m✓\x1b[0m] {message}");
+    }
+
+    fn fail(&mut self, message: &str, hint: &str)
+    {
+        self.0 += 1;
+        println!("[\x1b[31m✗\x1b[0m] {message}\n    \x1b[33mhint:\x1b[0m {hint}");
+    }
+
+    fn check(&mut self, ok: bool, message: &str, hint: &str)
+    {
+        if ok { self.pass(message) } else { self.fail(message, hint) }
+    }
+}
+
+// Check what pindd needs from the system and the user, and why it would grab each device.
+// Exits with the number of failed checks
+pub fn run(name: &str, config: Option<PathBuf>, env_file: Option<&Path>) -> !
+{
+    let mut doctor = Doctor(0);
+    let root = unsafe { libc::geteuid() } == 0;
+
+    let Some(user) = user::lookup(name) else {
+        doctor.fail(&format!("user {name} doesn't exist"), "pass the user pindd runs for: `pindd doctor USER`");
+        exit(1);
+    };
+    doctor.pass(&format!("user {} exists (uid {}, home {})", user.name, user.uid, user.home.display()));
+    if let Some(env_file) = env_file && let Err(e) = environ::load(env_file, user.uid) {
+        doctor.fail(&format!("environment file refused: {e}"), "run `pindc env` as the user to write it again");
+    }
+    let shell = &user.shell;
+    doctor.check(executable(shell), &format!("login shell {} is executable", shell.display()),
+        &format!("install it or change it with `chsh -s /bin/sh {}`, or set `shell` in the config", user.name));
+
+    devices(&mut doctor, &user, root);
+    uinput(&mut doctor, root);
+    settings(&mut doctor, &user, config);
+    grabs(&mut doctor, root);
+    exit(doctor.0.min(255) as i32)
+}
+
+fn executable(path: &Path) -> bool
+{
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+// Every event device has to be readable to find the keyboards among them
+fn devices(doctor: &mut Doctor, user: &User, root: bool)
+{
+    let events: Vec<PathBuf> = fs::read_dir(INPUT).map(|dir| {
+        dir.filter_map(Result::ok).map(|entry| entry.path())
+            .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(|name| name.starts_with("event")))
+            .collect()
+    }).unwrap_or_default();
+    if events.is_empty() {
+        return doctor.fail(&format!("no {INPUT}/event* devices"), "check that evdev is loaded (`modprobe evdev`) and that this isn't a container without /dev/input");
+    }
+
+    let denied: Vec<_> = events.iter().filter(|path| OpenOptions::new().read(true).open(path).is_err_and(|e| e.kind() == ErrorKind::PermissionDenied)).collect();
+    doctor.check(denied.is_empty(), &format!("{} of {} event devices are readable", events.len() - denied.len(), events.len()),
+        "start pindd as root with `pindc start`, or add the user to the input group for a user service");
+
+    // Only matters without root, the helper opens everything as root otherwise
+    if !root {
+        let member = group(GROUP).is_some_and(|gid| user.groups().contains(&gid));
+        doctor.check(member, &format!("{} is in the {GROUP} group", user.name),
+            &format!("`sudo usermod -aG {GROUP} {}` and log in again, or start pindd as root with `pindc start`", user.name));
+    }
+}
+
+fn group(name: &str) -> Option<libc::gid_t>
+{
+    let name = CString::new(name).ok()?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    (!entry.is_null()).then(|| unsafe { (*entry).gr_gid })
+}
+
+// Forwarding keys needs the uinput module and write access to its device
+fn uinput(doctor: &mut Doctor, root: bool)
+{
+    let loaded = Path::new("/sys/class/misc/uinput").exists()
+        || fs::read_to_string("/proc/modules").is_ok_and(|modules| modules.lines().any(|line| line.starts_with("uinput ")));
+    doctor.check(loaded, "the uinput module is loaded",
+        "`sudo modprobe uinput`, and `echo uinput | sudo tee /etc/modules-load.d/uinput.conf` to load it at boot");
+
+    match OpenOptions::new().read(true).write(true).open(UINPUT) {
+        Ok(_) => doctor.pass(&format!("{UINPUT} is writable")),
+        Err(e) if e.kind() == ErrorKind::NotFound => doctor.fail(&format!("{UINPUT} doesn't exist"), "load the uinput module, see above"),
+        Err(e) => doctor.fail(&format!("{UINPUT} can't be opened: {e}"), if root {
+            "something else keeps root from it, check `ls -l /dev/uinput` and the security modules (SELinux, AppArmor)"
+        } else {
+            "start pindd as root with `pindc start`, or give the user access with a udev rule like\n          KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\", OPTIONS+=\"static_node=uinput\""
+        }),
+    }
+}
+
+// The config pindd would load
+fn settings(doctor: &mut Doctor, user: &User, explicit: Option<PathBuf>)
+{
+    let path = config::resolve(explicit, user);
+    if !path.exists() {
+        return doctor.fail(&format!("config {} doesn't exist", path.display()), "create it, the README has an example");
+    }
+    let config = match load_config(&path, user) {
+        Ok(config) => config,
+        Err(e) => return doctor.fail(&format!("config {} doesn't load: {e}", path.display()), "fix it and check it with `pindc check`"),
+    };
+    doctor.check(!config.bindings.is_empty(), &format!("config {} loads with {} bindings", path.display(), config.bindings.len()),
+        "add bindings like `meta + enter => foot`");
+    for problem in check(&config) { doctor.fail(&problem, "see the Config section of the README"); }
+    if let Some(shell) = &config.shell {
+        doctor.check(executable(shell), &format!("config shell {} is executable", shell.display()), "install it or change `shell` in the config");
+    }
+}
+
+// What `keyboards()` decides for every device it can open
+fn grabs(doctor: &mut Doctor, root: bool)
+{
+    let devices: Vec<_> = enumerate().collect();
+    let grabbed = devices.iter().filter(|(_, device)| is_keyboard(device)).count();
+    doctor.check(grabbed > 0, &format!("{grabbed} of {} devices would be grabbed", devices.len()), if root {
+        "no device has both KEY_A and KEY_ENTER, is a keyboard plugged in?"
+    } else {
+        "devices pindd can't open are missing, run `sudo pindd doctor` to see them all"
+    });
+    for (path, device) in &devices {
+        let (grab, reason) = verdict(device);
+        println!("    {} {:<24} {}: {reason}", path.display(), device.name().unwrap_or("unnamed"), if grab { "grabbed" } else { "skipped" });
+    }
+}
@@ -0,0 +1,80 @@
+use crate::warn;
+use std::{env::vars_os, ffi::OsString, fs::{File, OpenOptions}, io::Read, os::unix::{ffi::OsStringExt, fs::{MetadataExt, OpenOptionsExt}}, path::{Path, PathBuf}, sync::{Mutex, PoisonError}};
+
+// The user's environment, NUL separated `NAME=value` in a file only they can write.
+// It is read again whenever it's replaced, so a new session reaches new commands
+struct Environ
+{
+    path: PathBuf,
+    uid: u32,
+    stamp: (u64, i64, i64), // inode and mtime of what `vars` came from
+    vars: Vec<(OsString, OsString)>,
+}
+
+static ENVIRON: Mutex<Option<Environ>> = Mutex::new(None);
+
+fn open(path: &Path, uid: u32) -> Result<File, String>
+{
+    let file = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_CLOEXEC).open(path)
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    let meta = file.metadata().map_err(|e| format!("{}: {e}", path.display()))?;
+    if !meta.is_file() { return Err(format!("{} is not a regular file", path.display())); }
+    if meta.uid() != uid { return Err(format!("{} is not owned by the user", path.display())); }
+    if meta.mode() & 0o022 != 0 { return Err(format!("{} is writable by others", path.display())); }
+    Ok(file)
+}
+
+impl Environ
+{
+    fn read(&mut self) -> Result<(), String>
+    {
+        let mut file = open(&self.path, self.uid)?;
+        let meta = file.metadata().map_err(|e| e.to_string())?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).map_err(|e| format!("{}: {e}", self.path.display()))?;
+
+        self.stamp = (meta.ino(), meta.mtime(), meta.mtime_nsec());
+        self.vars = content.split(|&b| b == 0)
+            .filter_map(|var| {
+                let eq = var.iter().position(|&b| b == b'=').filter(|&eq| eq > 0)?;
+                Some((OsString::from_vec(var[..eq].to_vec()), OsString::from_vec(var[eq + 1..].to_vec())))
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn changed(&self) -> bool
+    {
+        self.path.symlink_metadata().is_ok_and(|meta| (meta.ino(), meta.mtime(), meta.mtime_nsec()) != self.stamp)
+    }
+}
+
+// Use the environment from `path` instead of the daemon's own. It may not exist yet when
+// pindd starts with the system, commands get a minimal environment until it is written
+pub fn load(path: &Path, uid: u32) -> Result<(), String>
+{
+    let mut environ = Environ { path: path.to_path_buf(), uid, stamp: (0, 0, 0), vars: Vec::new() };
+    if path.symlink_metadata().is_ok() {
+        environ.read()?;
+    } else {
+        warn("Environment", &format!("{} doesn't exist yet, run `pindc env` in the session", path.display()));
+    }
+    *ENVIRON.lock().unwrap_or_else(PoisonError::into_inner) = Some(environ);
+    Ok(())
+}
+
+// The environment commands start from, refreshed if the file was replaced
+pub fn vars() -> Vec<(OsString, OsString)>
+{
+    let mut environ = ENVIRON.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(environ) = environ.as_mut() else { return vars_os().collect() };
+    if environ.changed() && let Err(e) = environ.read() {
+        warn("Environment", &format!("Keeping the previous environment, {e}"));
+    }
+    environ.vars.clone()
+}
+
+pub fn var(name: &str) -> Option<OsString>
+{
+    vars().into_iter().find(|(var, _)| var == name).map(|(_, value)| value)
+}
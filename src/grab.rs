@@ -0,0 +1,46 @@
+use evdev::Device;
+use std::{io, os::fd::{AsRawFd, RawFd}, path::{Path, PathBuf}, sync::{Mutex, PoisonError}};
+
+const EVIOCGRAB: libc::Ioctl = libc::_IOW::<libc::c_int>(b'E' as u32, 0x90);
+
+// Every grabbed keyboard, so they can all be released from any thread
+static GRABBED: Mutex<Vec<(RawFd, PathBuf)>> = Mutex::new(Vec::new());
+
+// Grabs a device for as long as it lives, declare it after the device so it is dropped first
+pub struct Grab(RawFd);
+
+impl Grab
+{
+    pub fn new(device: &mut Device, path: &Path) -> io::Result<Grab>
+    {
+        device.grab()?;
+        GRABBED.lock().unwrap_or_else(PoisonError::into_inner).push((device.as_raw_fd(), path.to_path_buf()));
+        Ok(Grab(device.as_raw_fd()))
+    }
+
+    pub fn release(self, device: &mut Device) -> io::Result<()>
+    {
+        device.ungrab()
+    }
+}
+
+impl Drop for Grab
+{
+    fn drop(&mut self)
+    {
+        GRABBED.lock().unwrap_or_else(PoisonError::into_inner).retain(|&(fd, _)| fd != self.0);
+    }
+}
+
+// Hand every keyboard back to the compositor, whatever state their workers are in
+pub fn release_all()
+{
+    for &(fd, _) in GRABBED.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        unsafe { libc::ioctl(fd, EVIOCGRAB, 0) };
+    }
+}
+
+pub fn grabbed() -> Vec<PathBuf>
+{
+    GRABBED.lock().unwrap_or_else(PoisonError::into_inner).iter().map(|(_, path)| path.clone()).collect()
+}
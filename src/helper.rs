@@ -0,0 +1,227 @@
+use crate::{config::{Binding, Config, load_config, renumber}, error, is_keyboard, procs, run, signals, user::User, warn};
+use evdev::{Device, uinput::{VirtualDevice, VirtualDeviceBuilder}};
+use std::{fs::OpenOptions, hash::{DefaultHasher, Hash, Hasher}, io, os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd}, path::Path, process::exit, sync::{Mutex, OnceLock, PoisonError}};
+
+const INPUT  :&str = "/dev/input/event";
+const UINPUT :&str = "/dev/uinput";
+
+// The engine's end of the socket to the root helper, unset when pindd isn't split
+static HELPER: OnceLock<Mutex<OwnedFd>> = OnceLock::new();
+
+// Fork into a root helper, which only opens keyboards and /dev/uinput and runs `@as` bindings
+// (their output goes to stderr, the log is the engine's), and an engine running as the user, which does everything else. Only the engine returns.
+// Nothing to split when pindd doesn't run as root, or runs for root
+pub fn split(user: &User)
+{
+    if unsafe { libc::geteuid() } != 0 || user.uid == 0 { return; }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC, 0, fds.as_mut_ptr()) } < 0 {
+        error("Helper", &format!("Failed to create socket: {}", io::Error::last_os_error()));
+    }
+    let (helper, engine) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let parent = unsafe { libc::getpid() };
+
+    match unsafe { libc::fork() } {
+        -1 => error("Helper", &format!("Failed to fork: {}", io::Error::last_os_error())),
+        0 => {
+            drop(helper);
+            if let Err(e) = user.become_user() { error("Helper", &format!("Failed to drop privileges: {e}")); }
+            // The engine goes down with the helper. Changing the uid clears the death signal, so it is set after
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) };
+            if unsafe { libc::getppid() } != parent { exit(0); }
+            let _ = HELPER.set(Mutex::new(engine));
+        }
+        child => {
+            drop(engine);
+            serve(helper, child, user)
+        }
+    }
+}
+
+// Answer the engine's requests until it exits, then stop the commands started for it
+// and exit like the engine did
+fn serve(socket: OwnedFd, engine: libc::pid_t, user: &User) -> !
+{
+    signals();
+    let mut config: Option<Config> = None;
+    let mut buffer = [0; libc::PATH_MAX as usize + 16];
+    loop {
+        let len = match recv(socket.as_fd(), &mut buffer) {
+            Ok((0, _)) => break, // The engine is gone
+            Ok((len, _)) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        let request = String::from_utf8_lossy(&buffer[..len]);
+        let (verb, argument) = request.split_once(' ').unwrap_or((&request, ""));
+        let reply = match verb {
+            "open"   => open_keyboard(Path::new(argument)).map(Some),
+            "uinput" => open(Path::new(UINPUT)).map(Some),
+            // The engine loaded a config, `@as` bindings follow from the helper's own reading of it.
+            // Nothing of the previous one is kept, its ids may not be the engine's anymore,
+            // and commands of bindings that are gone or changed are stopped like in the engine
+            "config" => {
+                let old = config.take();
+                let loaded = load(Path::new(argument), user).map(|loaded| { config = Some(loaded); None });
+                procs::reload(&renumber(old.as_ref().map_or(&[], |old| &old.bindings[..]),
+                    config.as_ref().map_or(&[], |new| &new.bindings[..])));
+                loaded
+            }
+            "run"    => {
+                // Only bindings of the helper's own config, only those that need it, and only the
+                // one the engine means: right after a reload the id may be another binding's
+                let (id, print) = argument.split_once(' ').unwrap_or((argument, ""));
+                let id = id.parse().unwrap_or(usize::MAX);
+                let binding = config.as_ref().and_then(|config| config.bindings.get(id))
+                    .filter(|binding| binding.run_as.as_ref().is_some_and(|run_as| run_as.uid != user.uid))
+                    .filter(|binding| format!("{:x}", fingerprint(binding)) == print);
+                if let Some(binding) = binding { run(id, binding, user); }
+                continue;
+            }
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+        let sent = match reply {
+            Ok(fd) => send(socket.as_fd(), b"+", fd.as_ref().map(AsFd::as_fd)),
+            Err(e) => send(socket.as_fd(), format!("-{}", e.raw_os_error().unwrap_or(libc::EIO)).as_bytes(), None),
+        };
+        if sent.is_err() { break; }
+    }
+    procs::kill_all(libc::SIGTERM);
+    let mut status = 0;
+    unsafe { libc::waitpid(engine, &mut status, 0) };
+    exit(if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 1 })
+}
+
+// The path comes from the engine, so root goes through the same checks, on the file it opened:
+// one the user can read, and `@as` only if it may. Not loading means no `@as` bindings, never an exit
+fn load(path: &Path, user: &User) -> io::Result<Config>
+{
+    load_config(path, user).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+// Only keyboards are handed out, the engine has no business with other input devices
+fn open_keyboard(path: &Path) -> io::Result<OwnedFd>
+{
+    let path = path.canonicalize()?;
+    let event = path.to_str().and_then(|path| path.strip_prefix(INPUT))
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()));
+    if !event { return Err(io::Error::from_raw_os_error(libc::EACCES)); }
+
+    let fd = open(&path)?;
+    if !is_keyboard(&Device::from_fd(fd.try_clone()?)?) { return Err(io::Error::from_raw_os_error(libc::EACCES)); }
+    Ok(fd)
+}
+
+fn open(path: &Path) -> io::Result<OwnedFd>
+{
+    OpenOptions::new().read(true).write(true).open(path)
+        .or_else(|_| OpenOptions::new().read(true).open(path))
+        .map(OwnedFd::from)
+}
+
+// Open a keyboard through the helper, or directly when pindd isn't split
+pub fn open_device(path: &Path) -> io::Result<Device>
+{
+    match HELPER.get() {
+        Some(socket) => Device::from_fd(request(socket, &format!("open {}", path.display()))?.ok_or(io::ErrorKind::InvalidData)?),
+        None => Device::open(path),
+    }
+}
+
+// Start a virtual device on /dev/uinput opened by the helper, or directly when pindd isn't split
+pub fn uinput<'a>() -> io::Result<VirtualDeviceBuilder<'a>>
+{
+    match HELPER.get() {
+        Some(socket) => Ok(VirtualDeviceBuilder::from_fd(request(socket, "uinput")?.ok_or(io::ErrorKind::InvalidData)?)),
+        None => VirtualDevice::builder(),
+    }
+}
+
+// The same for the same binding in the engine and the helper, they are one binary
+fn fingerprint(binding: &Binding) -> u64
+{
+    let mut hasher = DefaultHasher::new();
+    (&binding.combo, &binding.command, binding.run_as.as_ref().map(|user| user.uid)).hash(&mut hasher);
+    hasher.finish()
+}
+
+// Have the helper run an `@as` binding, false when there is no helper to do it
+pub fn run_as(id: usize, binding: &Binding) -> bool
+{
+    let Some(socket) = HELPER.get() else { return false };
+    let socket = socket.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(e) = send(socket.as_fd(), format!("run {id} {:x}", fingerprint(binding)).as_bytes(), None) {
+        warn("Command execution failed", &format!("helper unreachable: {e}"));
+    }
+    true
+}
+
+// Have the helper read the config the engine loaded, for its `@as` bindings
+pub fn load_as(path: &Path)
+{
+    if let Some(socket) = HELPER.get() && let Err(e) = request(socket, &format!("config {}", path.display())) {
+        warn("Helper", &format!("Refused {}, its @as bindings won't run: {e}", path.display()));
+    }
+}
+
+// One request at a time, so every reply reaches the thread that asked for it
+fn request(socket: &Mutex<OwnedFd>, message: &str) -> io::Result<Option<OwnedFd>>
+{
+    let socket = socket.lock().unwrap_or_else(PoisonError::into_inner);
+    send(socket.as_fd(), message.as_bytes(), None)?;
+    let mut reply = [0; 16];
+    let (len, fd) = recv(socket.as_fd(), &mut reply)?;
+    match (&reply[..len], fd) {
+        (b"+", fd) => Ok(fd),
+        (b"", _) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "the helper is gone")),
+        (reply, _) => {
+            let errno = std::str::from_utf8(&reply[1..]).ok().and_then(|errno| errno.parse().ok());
+            Err(io::Error::from_raw_os_error(errno.unwrap_or(libc::EIO)))
+        }
+    }
+}
+
+fn send(socket: BorrowedFd, message: &[u8], fd: Option<BorrowedFd>) -> io::Result<()>
+{
+    let mut iov = libc::iovec { iov_base: message.as_ptr() as *mut _, iov_len: message.len() };
+    let mut control = [0_u64; 4];
+    let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    if let Some(fd) = fd {
+        unsafe {
+            header.msg_control = control.as_mut_ptr().cast();
+            header.msg_controllen = libc::CMSG_SPACE(size_of::<RawFd>() as u32) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&header);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+            libc::CMSG_DATA(cmsg).cast::<RawFd>().write_unaligned(fd.as_raw_fd());
+        }
+    }
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &header, libc::MSG_NOSIGNAL) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv(socket: BorrowedFd, buffer: &mut [u8]) -> io::Result<(usize, Option<OwnedFd>)>
+{
+    let mut iov = libc::iovec { iov_base: buffer.as_mut_ptr().cast(), iov_len: buffer.len() };
+    let mut control = [0_u64; 4];
+    let mut header: libc::msghdr = unsafe { std::mem::zeroed() };
+    header.msg_iov = &mut iov;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 { return Err(io::Error::last_os_error()); }
+    let fd = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&header);
+        (!cmsg.is_null() && (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS)
+            .then(|| OwnedFd::from_raw_fd(libc::CMSG_DATA(cmsg).cast::<RawFd>().read_unaligned()))
+    };
+    Ok((len as usize, fd))
+}
@@ -0,0 +1,19 @@
+pub mod config;
+pub mod control;
+pub mod environ;
+pub mod lock;
+pub mod log;
+pub mod user;
+
+use std::process::exit;
+
+pub fn error(title: &str, message: &str) -> !
+{
+    eprintln!("[\x1b[33mE\x1b[0m] \x1b[31m{title}:\x1b[0m {message}.");
+    exit(1)
+}
+
+pub fn warn(title: &str, message: &str)
+{
+    eprintln!("[\x1b[33mW\x1b[0m] \x1b[33m{title}:\x1b[0m {message}.");
+}
@@ -0,0 +1,86 @@
+use crate::{environ, error};
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Seek, Write}, os::{fd::AsRawFd, unix::fs::{OpenOptionsExt, PermissionsExt}}, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+
+pub const RUN :&str = "/run/pind";
+const HANDOVER:Duration = Duration::from_secs(5);     // how long --replace waits for the old instance
+const POLL    :Duration = Duration::from_millis(50);
+
+// /run/pind for a pindd started as root, $XDG_RUNTIME_DIR/pind for one running as the user,
+// like a systemd user service
+pub fn run_dir() -> PathBuf
+{
+    if unsafe { libc::geteuid() } == 0 { return PathBuf::from(RUN); }
+    environ::var("XDG_RUNTIME_DIR").map(PathBuf::from).filter(|dir| dir.is_absolute())
+        .map_or_else(|| PathBuf::from(RUN), |dir| dir.join("pind"))
+}
+
+// <user>.pid in the run directory, locked for as long as the user's pindd runs
+pub fn pidfile(user: &str) -> PathBuf
+{
+    run_dir().join(format!("{user}.pid"))
+}
+
+// Become the only pindd of the user, or ask the running one to hand over with `replace`.
+// The lock lives as long as the returned file, the helper and the engine both share it
+pub fn acquire(user: &str, replace: bool) -> File
+{
+    let dir = run_dir();
+    let mode = if dir == Path::new(RUN) { 0o755 } else { 0o700 };
+    let _ = fs::create_dir_all(&dir).and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(mode)));
+    let path = pidfile(user);
+    let mut file = OpenOptions::new().read(true).write(true).create(true).mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)
+        .unwrap_or_else(|e| error("Instance", &format!("Failed to open {}: {e}", path.display())));
+
+    let started = Instant::now();
+    let mut signalled = false;
+    while let Err(e) = lock(&file) {
+        if e.raw_os_error() != Some(libc::EWOULDBLOCK) { error("Instance", &format!("Failed to lock {}: {e}", path.display())); }
+        let pid = running(&mut file);
+        if !replace {
+            let pid = pid.map_or(String::new(), |pid| format!(" (pid {pid})"));
+            error("Instance", &format!("pindd is already running for {user}{pid}, use --replace to take over"));
+        }
+        // The pid may not be written yet if it is starting too, try again until it is
+        if !signalled && let Some(pid) = pid {
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+            signalled = true;
+        }
+        if started.elapsed() >= HANDOVER { error("Instance", &format!("pindd for {user} didn't exit, giving up")); }
+        thread::sleep(POLL);
+    }
+
+    let written = file.set_len(0).and_then(|_| file.rewind()).and_then(|_| writeln!(file, "{}", std::process::id()));
+    if let Err(e) = written { error("Instance", &format!("Failed to write {}: {e}", path.display())); }
+    file
+}
+
+// The users whose pindd holds its lock in `dir`, with its pid. The lock is only tried shared
+// and let go at once, an instance starting at that moment would have to be very unlucky
+pub fn holders(dir: &Path) -> Vec<(String, Option<i32>)>
+{
+    let Ok(entries) = fs::read_dir(dir) else { return Vec::new() };
+    entries.filter_map(Result::ok).filter_map(|entry| {
+        let path = entry.path();
+        let user = path.file_name()?.to_str()?.strip_suffix(".pid")?.to_string();
+        let mut file = OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW).open(&path).ok()?;
+        let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } < 0
+            && io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK);
+        locked.then(|| (user, running(&mut file)))
+    }).collect()
+}
+
+fn lock(file: &File) -> io::Result<()>
+{
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 { return Err(io::Error::last_os_error()); }
+    Ok(())
+}
+
+// The pid of the instance holding the lock
+fn running(file: &mut File) -> Option<i32>
+{
+    let mut pid = String::new();
+    file.rewind().and_then(|_| file.read_to_string(&mut pid)).ok()?;
+    pid.trim().parse().ok().filter(|&pid| pid > 0)
+}
@@ -0,0 +1,79 @@
+use crate::{environ, user::User};
+use std::{ffi::CString, fs::{self, File, OpenOptions}, io::Write, path::PathBuf, sync::{Mutex, PoisonError}};
+
+const MAX :u64 = 1 << 20; // 1MiB, past that pind.log is rotated to pind.log.1
+
+// The command log, written to stderr until `open` finds a place for it
+struct Log
+{
+    path: PathBuf,
+    file: Option<File>,
+}
+
+static LOG: Mutex<Option<Log>> = Mutex::new(None);
+
+// $XDG_STATE_HOME/pind/pind.log of the user
+pub fn path(user: &User) -> PathBuf
+{
+    let state = environ::var("XDG_STATE_HOME").filter(|state| !state.is_empty()).map(PathBuf::from)
+        .unwrap_or_else(|| user.home.join(".local/state"));
+    state.join("pind/pind.log")
+}
+
+// Open the log of the user. Only call it as the user: every directory on the way is theirs
+// to replace with a symlink, root would create and write files wherever they point
+pub fn open(user: &User)
+{
+    let path = path(user);
+    if let Some(dir) = path.parent() && let Err(e) = fs::create_dir_all(dir) {
+        return eprintln!("Failed to create {}: {e}", dir.display());
+    }
+
+    let mut log = Log { path, file: None };
+    log.file = log.reopen();
+    *LOG.lock().unwrap_or_else(PoisonError::into_inner) = Some(log);
+}
+
+impl Log
+{
+    fn reopen(&self) -> Option<File>
+    {
+        OpenOptions::new().create(true).append(true).open(&self.path)
+            .map_err(|e| eprintln!("Failed to open {}: {e}", self.path.display())).ok()
+    }
+
+    fn rotate(&mut self)
+    {
+        let full = self.file.as_ref().and_then(|file| file.metadata().ok()).is_some_and(|meta| meta.len() >= MAX);
+        if !full { return; }
+        let _ = fs::rename(&self.path, self.path.with_extension("log.1"));
+        self.file = self.reopen();
+    }
+}
+
+fn now() -> String
+{
+    let mut buffer = [0u8; 32];
+    let format = CString::new("%F %T").unwrap_or_default();
+    let len = unsafe {
+        let time = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&time, &mut tm);
+        libc::strftime(buffer.as_mut_ptr().cast(), buffer.len(), format.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+// One line of the log, prefixed with the binding it is about
+pub fn write(binding: &str, message: &str)
+{
+    let line = format!("{} [{binding}] {message}\n", now());
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    match log.as_mut() {
+        Some(log) if log.file.is_some() => {
+            log.rotate();
+            if let Some(file) = log.file.as_mut() { let _ = file.write_all(line.as_bytes()); }
+        }
+        _ => eprint!("{line}"),
+    }
+}
@@ -1,286 +1,619 @@
-use evdev::{AttributeSet, Device, KeyCode, enumerate, EventType, uinput::VirtualDevice};
-use std::{env::{var,args},fs::{read_to_string,canonicalize}, path::PathBuf, process::{exit,Command, Stdio}, sync::{Arc, OnceLock}, thread};
+mod capture;
+mod devices;
+mod doctor;
+mod grab;
+mod helper;
+mod procs;
+mod record;
+mod systemd;
 
-const DELAY  :u64  =  25; // 25ms
-const CONFIG :&str =  "~/.config/pind/pindrc";
+use pind::{config, control, environ, error, lock, log, user, warn};
+use config::{Binding, Config, Policy, check, load_config};
+use grab::{Grab, grabbed, release_all};
+use user::User;
+use evdev::{AttributeSet, Device, KeyCode, InputEvent, enumerate, EventType, uinput::VirtualDevice};
+use std::{env::{var, args}, io::{self, BufRead, BufReader, Write}, mem::MaybeUninit, os::{fd::AsRawFd, unix::net::UnixListener}, panic, path::{Path, PathBuf}, process::{exit,Command, Stdio}, sync::{Arc, PoisonError, RwLock}, thread, time::{Duration, Instant}};
 
-static BINDINGS: OnceLock<Arc<Vec<(AttributeSet<KeyCode>, String)>>> = OnceLock::new();
+const DELAY   :u64  =  25;   // 25ms
+const RELEASE :u64  =  2000; // 2s to let go of the keys before grabbing
+const WAKE    :i32  =  100;  // 100ms, longest wait for events before checking the escape combo
+const BACKOFF :(u64, u64) = (1, 60); // seconds before restarting a crashed worker, doubled up to the max
+const WORKER  :&str =  "pind-keys";
+const FAILS   :u32  =  5;    // emits failing in a row before the virtual device is rebuilt
 
-fn get_bindings() -> Arc<Vec<(AttributeSet<KeyCode>, String)>>
+// The current config, replaced on reload. Workers notice and start over with the new one
+static SETTINGS: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+struct Args
 {
-    Arc::clone(BINDINGS.get_or_init(|| Arc::new(load_config(CONFIG))))
+    user: String,
+    listen_only: bool,
+    check: bool,
+    doctor: bool, // `pindd doctor [USER]`
+    replace: bool, // stop the user's running pindd instead of refusing to start
+    print_unit: Option<String>, // system or user
+    list_devices: bool,
+    json: bool, // for --list-devices
+    record: bool,
+    env_file: Option<PathBuf>, // written by pindc, otherwise the daemon's own environment is used
+    config: Option<PathBuf>,
 }
 
-fn error(title: &str, message: &str) -> !
+fn settings() -> Arc<Config>
 {
-    eprintln!("[\x1b[33mE\x1b[0m] \x1b[31m{title}:\x1b[0m {message}.");
-    exit(1)
+    let settings = SETTINGS.read().unwrap_or_else(PoisonError::into_inner);
+    Arc::clone(settings.as_ref().unwrap_or_else(|| error("Config", "used before it is loaded")))
 }
 
-fn run(command: &str, user: &str)
+fn get_config(args: &Args, user: &User) -> Arc<Config>
 {
-    let shell = var("SHELL").unwrap_or_else(|_| "sh".into());
-    if let Err(e) = Command::new("runuser")
-        .args(["-u", user, "--", &shell, "-c", command])
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn() { error("Command execution failed", &e.to_string()) }
+    let mut config = load_config(&config::resolve(args.config.clone(), user), user).unwrap_or_else(|e| error("Config", &e));
+    config.listen_only |= args.listen_only;
+    let config = Arc::new(config);
+    helper::load_as(&config.path);
+    *SETTINGS.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::clone(&config));
+    config
+}
+
+// Read the config again from the same path, commands of bindings that are still there keep
+// running. A config that doesn't load leaves the current one in place
+fn reload(user: &User, listen_only: bool) -> Result<Arc<Config>, String>
+{
+    let old = settings();
+    let mut config = load_config(&old.path, user)?;
+    config.listen_only |= listen_only;
+    let config = Arc::new(config);
+    // Workers move to the new config first, the helper only runs what matches its own
+    *SETTINGS.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::clone(&config));
+    procs::reload(&config::renumber(&old.bindings, &config.bindings));
+    helper::load_as(&config.path);
+    systemd::notify(&format!("STATUS=Reloaded {} bindings", config.bindings.len()));
+    Ok(config)
+}
+
+fn parse_args() -> Args
+{
+    let mut parsed = Args { user: String::new(), listen_only: false, check: false, doctor: false, replace: false, print_unit: None, list_devices: false, json: false, record: false, env_file: None, config: None };
+    let mut args = args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--listen-only" => parsed.listen_only = true,
+            "--check"       => parsed.check = true,
+            "--replace"     => parsed.replace = true,
+            "--list-devices" => parsed.list_devices = true,
+            "--json"        => parsed.json = true,
+            "--record"      => parsed.record = true,
+            "--env-file"    => parsed.env_file = Some(args.next().unwrap_or_else(|| error("Argument", "--env-file needs a path")).into()),
+            "--print-unit"  => parsed.print_unit = Some(args.next().unwrap_or_else(|| error("Argument", "--print-unit needs system or user"))),
+            "--config"      => parsed.config = Some(args.next().unwrap_or_else(|| error("Argument", "--config needs a path")).into()),
+            _ if arg.starts_with('-') => error("Argument", &format!("unknown option {arg}")),
+            "doctor" if !parsed.doctor && parsed.user.is_empty() => parsed.doctor = true,
+            _ => parsed.user = arg,
+        }
+    }
+    // Checking a config or the system doesn't need root, it's the caller's by default, the one
+    // behind sudo or doas too
+    if parsed.user.is_empty() && (parsed.check || parsed.doctor) {
+        parsed.user = ["SUDO_USER", "DOAS_USER", "USER"].iter().find_map(|name| var(name).ok().filter(|user| !user.is_empty())).unwrap_or_default();
+    }
+    if parsed.user.is_empty() && parsed.print_unit.is_none() && !parsed.list_devices && !parsed.record { error("USER", "Username argument required"); }
+    parsed
 }
 
+// Start the binding's command, following its policy if the previous one is still running.
+// `@as` bindings for someone else are left to the root helper when there is one
+fn run(id: usize, binding: &Binding, user: &User)
+{
+    if binding.run_as.as_ref().is_some_and(|run_as| run_as.uid != user.uid) && helper::run_as(id, binding) { return; }
+    match binding.policy {
+        Policy::Single if procs::is_running(id) => return,
+        Policy::Queue => {
+            let (binding, user) = (binding.clone(), user.clone());
+            if let Err(launch) = procs::enqueue(id, Box::new(move || spawn(id, &binding, &user))) { launch(); }
+            return;
+        }
+        Policy::Restart => procs::kill(id, libc::SIGTERM),
+        _ => {}
+    }
+    spawn(id, binding, user);
+}
 
-fn key_to_keycode(input: &str) -> AttributeSet<KeyCode> 
+fn spawn(id: usize, binding: &Binding, user: &User)
 {
-    let mut attribute_set = AttributeSet::new();
-
-    for key in input.replace(' ',"").split('+')  {
-        match key.to_lowercase().as_str(){
-            "meta_left"  | "meta"  => attribute_set.insert(KeyCode::KEY_LEFTMETA),
-            "ctrl_left"  | "ctrl"  => attribute_set.insert(KeyCode::KEY_LEFTCTRL),
-            "shift_left" | "shift" => attribute_set.insert(KeyCode::KEY_LEFTSHIFT),
-            "alt_left"   | "alt"   => attribute_set.insert(KeyCode::KEY_LEFTALT),
-            "back_slash" | "\\"    => attribute_set.insert(KeyCode::KEY_BACKSLASH),
-            "slash"      | "/"     => attribute_set.insert(KeyCode::KEY_SLASH),
-            "enter"     | "return" => attribute_set.insert(KeyCode::KEY_ENTER),
-            "ctrl_right"  => attribute_set.insert(KeyCode::KEY_RIGHTCTRL),
-            "shift_right" => attribute_set.insert(KeyCode::KEY_RIGHTSHIFT),
-            "meta_right"  => attribute_set.insert(KeyCode::KEY_RIGHTMETA),
-            "alt_right"   => attribute_set.insert(KeyCode::KEY_RIGHTALT),
-            "capslock"    => attribute_set.insert(KeyCode::KEY_CAPSLOCK),
-            "tab" => attribute_set.insert(KeyCode::KEY_TAB),
-            "0" => attribute_set.insert(KeyCode::KEY_0),
-            "1" => attribute_set.insert(KeyCode::KEY_1),
-            "2" => attribute_set.insert(KeyCode::KEY_2),
-            "3" => attribute_set.insert(KeyCode::KEY_3),
-            "4" => attribute_set.insert(KeyCode::KEY_4),
-            "5" => attribute_set.insert(KeyCode::KEY_5),
-            "6" => attribute_set.insert(KeyCode::KEY_6),
-            "7" => attribute_set.insert(KeyCode::KEY_7),
-            "8" => attribute_set.insert(KeyCode::KEY_8),
-            "9" => attribute_set.insert(KeyCode::KEY_9),
-            "a" => attribute_set.insert(KeyCode::KEY_A),
-            "b" => attribute_set.insert(KeyCode::KEY_B),
-            "c" => attribute_set.insert(KeyCode::KEY_C),
-            "d" => attribute_set.insert(KeyCode::KEY_D),
-            "e" => attribute_set.insert(KeyCode::KEY_E),
-            "f" => attribute_set.insert(KeyCode::KEY_F),
-            "g" => attribute_set.insert(KeyCode::KEY_G),
-            "h" => attribute_set.insert(KeyCode::KEY_H),
-            "i" => attribute_set.insert(KeyCode::KEY_I),
-            "j" => attribute_set.insert(KeyCode::KEY_J),
-            "k" => attribute_set.insert(KeyCode::KEY_K),
-            "l" => attribute_set.insert(KeyCode::KEY_L),
-            "m" => attribute_set.insert(KeyCode::KEY_M),
-            "n" => attribute_set.insert(KeyCode::KEY_N),
-            "o" => attribute_set.insert(KeyCode::KEY_O),
-            "p" => attribute_set.insert(KeyCode::KEY_P),
-            "q" => attribute_set.insert(KeyCode::KEY_Q),
-            "r" => attribute_set.insert(KeyCode::KEY_R),
-            "s" => attribute_set.insert(KeyCode::KEY_S),
-            "t" => attribute_set.insert(KeyCode::KEY_T),
-            "u" => attribute_set.insert(KeyCode::KEY_U),
-            "v" => attribute_set.insert(KeyCode::KEY_V),
-            "w" => attribute_set.insert(KeyCode::KEY_W),
-            "x" => attribute_set.insert(KeyCode::KEY_X),
-            "y" => attribute_set.insert(KeyCode::KEY_Y),
-            "z" => attribute_set.insert(KeyCode::KEY_Z),
-            "up"    => attribute_set.insert(KeyCode::KEY_UP),
-            "down"  => attribute_set.insert(KeyCode::KEY_DOWN),
-            "right" => attribute_set.insert(KeyCode::KEY_RIGHT),
-            "left"  => attribute_set.insert(KeyCode::KEY_LEFT),
-            "dot" | "." => attribute_set.insert(KeyCode::KEY_DOT),
-            "comma" | "," => attribute_set.insert(KeyCode::KEY_COMMA),
-            "semicolon"  | ";"  => attribute_set.insert(KeyCode::KEY_SEMICOLON),
-            "apostrophe" | "\"" => attribute_set.insert(KeyCode::KEY_APOSTROPHE),
-            "leftbrace"  | "["  => attribute_set.insert(KeyCode::KEY_LEFTBRACE),
-            "rightbrace" | "]"  => attribute_set.insert(KeyCode::KEY_RIGHTBRACE),
-            "numlock"     => attribute_set.insert(KeyCode::KEY_NUMLOCK),
-            "scroll_lock"  => attribute_set.insert(KeyCode::KEY_SCROLLLOCK),
-            "minus" | "-" => attribute_set.insert(KeyCode::KEY_MINUS),
-            "equal" | "=" => attribute_set.insert(KeyCode::KEY_EQUAL),
-            "plus" => attribute_set.insert(KeyCode::KEY_KPPLUS),
-            "grave" | "`" => attribute_set.insert(KeyCode::KEY_GRAVE),
-            "space" => attribute_set.insert(KeyCode::KEY_SPACE),
-            "esc" => attribute_set.insert(KeyCode::KEY_ESC),
-            "f1"  => attribute_set.insert(KeyCode::KEY_F1),
-            "f2"  => attribute_set.insert(KeyCode::KEY_F2),
-            "f3"  => attribute_set.insert(KeyCode::KEY_F3),
-            "f4"  => attribute_set.insert(KeyCode::KEY_F4),
-            "f5"  => attribute_set.insert(KeyCode::KEY_F5),
-            "f6"  => attribute_set.insert(KeyCode::KEY_F6),
-            "f7"  => attribute_set.insert(KeyCode::KEY_F7),
-            "f8"  => attribute_set.insert(KeyCode::KEY_F8),
-            "f9"  => attribute_set.insert(KeyCode::KEY_F9),
-            "f10" => attribute_set.insert(KeyCode::KEY_F10),
-            "f11" => attribute_set.insert(KeyCode::KEY_F11),
-            "f12" => attribute_set.insert(KeyCode::KEY_F12),
-            "kp0"     | "keypad_0" => attribute_set.insert(KeyCode::KEY_KP0),
-            "kp1"     | "keypad_1" => attribute_set.insert(KeyCode::KEY_KP1),
-            "kp2"     | "keypad_2" => attribute_set.insert(KeyCode::KEY_KP2),
-            "kp3"     | "keypad_3" => attribute_set.insert(KeyCode::KEY_KP3),
-            "kp4"     | "keypad_4" => attribute_set.insert(KeyCode::KEY_KP4),
-            "kp5"     | "keypad_5" => attribute_set.insert(KeyCode::KEY_KP5),
-            "kp6"     | "keypad_6" => attribute_set.insert(KeyCode::KEY_KP6),
-            "kp7"     | "keypad_7" => attribute_set.insert(KeyCode::KEY_KP7),
-            "kp8"     | "keypad_8" => attribute_set.insert(KeyCode::KEY_KP8),
-            "kp9"     | "keypad_9" => attribute_set.insert(KeyCode::KEY_KP9),
-            "kpdot"   | "keypad_dot" => attribute_set.insert(KeyCode::KEY_KPDOT),
-            "kpplus"  | "keypad_plus" => attribute_set.insert(KeyCode::KEY_KPPLUS),
-            "kpminus" | "keypad_minus" => attribute_set.insert(KeyCode::KEY_KPMINUS),
-            _ => {error("Key", &format!("key {} is not exists",key))},
+    // Someone else than the daemon's user doesn't get the session environment
+    let (user, foreign) = match &binding.run_as {
+        Some(run_as) => (run_as, run_as.uid != user.uid),
+        None => (user, false),
+    };
+    let new = |program: &Path| if foreign { user.command_as(program) } else { user.command(program) };
+    let mut command = match &binding.argv {
+        Some(argv) => {
+            let mut command = new(Path::new(&argv[0]));
+            command.args(&argv[1..]);
+            command
+        }
+        None => {
+            let mut command = new(binding.shell.as_ref().unwrap_or(&user.shell));
+            command.args(["-c", &binding.command]);
+            command
+        }
+    };
+    command.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    // stdout and stderr share one pipe, so the log keeps their order
+    let output = if binding.log { capture(&mut command) } else { None };
+    let child = command.spawn();
+    drop(command); // Closes our copies of the pipe's write end, or the reader never sees EOF
+    match child {
+        Ok(child) => {
+            procs::track(child, id, &binding.combo, &binding.command, binding.timeout);
+            if let Some(output) = output {
+                let combo = binding.combo.clone();
+                thread::spawn(move || BufReader::new(output).lines().map_while(Result::ok).for_each(|line| log::write(&combo, &line)));
+            }
         }
+        Err(e) => warn("Command execution failed", &e.to_string()),
+    }
+}
+
+fn capture(command: &mut Command) -> Option<io::PipeReader>
+{
+    let (reader, writer) = io::pipe().map_err(|e| eprintln!("Failed to create pipe: {e}")).ok()?;
+    command.stdout(writer.try_clone().ok()?).stderr(writer);
+    Some(reader)
+}
+
+fn is_keyboard(device: &Device) -> bool
+{
+    device.supported_keys().is_some_and(|keys| keys.contains(KeyCode::KEY_A) && keys.contains(KeyCode::KEY_ENTER))
+}
+
+// Whether a device is grabbed, and why
+fn verdict(device: &Device) -> (bool, &'static str)
+{
+    match device.supported_keys() {
+        _ if is_keyboard(device) => (true, "has KEY_A and KEY_ENTER"),
+        None => (false, "no keys"),
+        Some(keys) if keys.contains(KeyCode::KEY_A) => (false, "no KEY_ENTER"),
+        Some(_) => (false, "no KEY_A"),
     }
-    attribute_set
 }
 
 fn keyboards() -> Vec<PathBuf>
 {
-    enumerate().filter_map(|(path, dev)| {
-        dev.supported_keys()
-            .filter(|keys| keys.contains(KeyCode::KEY_A) && keys.contains(KeyCode::KEY_ENTER))
-            .map(|_| path)
-    })
-    .collect()
+    enumerate().filter(|(_, dev)| is_keyboard(dev)).map(|(path, _)| path).collect()
 }
 
-fn load_config(config: &str) -> Vec<(AttributeSet<KeyCode>, String)>
+// Wait until every key is up, so the key that started us (usually Enter) is not
+// pressed on the physical device and released on the virtual one
+fn wait_release(device: &Device, delay: u64)
 {
-    let content = read_to_string(canonicalize(PathBuf::from(config.replace("~",&var("HOME").unwrap_or_default()))).unwrap_or_else(|e|{error("config",&e.to_string())}))
-        .unwrap_or_else(|e| error("Config file read failed", &e.to_string()));
-    
-    content.lines()
-        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
-        .filter_map(|line| {
-            let mut parts = line.splitn(2, "=>");
-            let key = parts.next()?.trim();
-            let cmd = parts.next()?.trim();
-            Some((key_to_keycode(key), cmd.to_string()))
-        })
-        .collect()
+    let mut waited = 0;
+    loop {
+        let held = device.get_key_state().unwrap_or_default();
+        if held.iter().next().is_none() || waited >= RELEASE { return; }
+        thread::sleep(Duration::from_millis(delay));
+        waited += delay;
+    }
 }
 
-fn read_keys(kc: &[(AttributeSet<KeyCode>, String)], kbs: PathBuf, delay: u64, user: String)
+// Throw away events queued before the grab, the compositor has already seen them
+fn drain(device: &mut Device)
+{
+    if device.set_nonblocking(true).is_err() { return; }
+    while let Ok(events) = device.fetch_events() { events.for_each(drop); }
+    let _ = device.set_nonblocking(false);
+}
+
+// Wait for events, giving up after `timeout` milliseconds
+fn readable(device: &Device, timeout: i32) -> bool
+{
+    let mut fds = libc::pollfd { fd: device.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut fds, 1, timeout) > 0 }
+}
+
+// Build a virtual device able to forward every key of the physical one
+fn build_virtual(device: &Device) -> Option<VirtualDevice>
 {
-    let mut state = vec![(0, 0); kc.len()];
-    let mut tick = 1;
-    let init = (320 + delay - 1) / delay;
-    
-    let mut device = match Device::open(&kbs) {
-        Ok(dev) => dev,
-        Err(_) => return, // Device no longer exists, exit thread
-    };
-    
     // Create a virtual device to forward non-shortcut keys
     // First, get all supported keys from the physical device to set up the virtual device properly
     let supported_keys = match device.supported_keys() {
         Some(keys) => keys,
-        _ => return eprintln!("Failed to get supported keys from device")
+        _ => { eprintln!("Failed to get supported keys from device"); return None }
     };
-    
-    let mut virtual_device = match VirtualDevice::builder() {
+
+    let virtual_device = match helper::uinput() {
         Ok(builder) => builder,
-        Err(e) => return eprintln!("Failed to create virtual device builder: {e}")
+        Err(e) => { eprintln!("Failed to create virtual device builder: {e}"); return None }
     };
-    
-    virtual_device = match virtual_device.name("pind-virtual-keyboard").with_keys(&supported_keys) {
+
+    let virtual_device = match virtual_device.name("pind-virtual-keyboard").with_keys(supported_keys) {
         Ok(builder) => builder,
-        Err(e) => return eprintln!("Failed to set up virtual device with keys: {e}")
+        Err(e) => { eprintln!("Failed to set up virtual device with keys: {e}"); return None }
     };
-    
-    let mut virtual_device = match virtual_device.build() {
-        Ok(dev) => dev,
-        Err(e) => return eprintln!("Failed to build virtual device: {e}")
-    };
-    
+
+    match virtual_device.build() {
+        Ok(dev) => Some(dev),
+        Err(e) => { eprintln!("Failed to build virtual device: {e}"); None }
+    }
+}
+
+// Build the virtual device and grab the physical one, everything not swallowed by
+// a binding is forwarded through it. Returns the keys already pressed on it
+fn take_over(device: &mut Device, path: &Path, delay: u64) -> Option<(VirtualDevice, AttributeSet<KeyCode>, Grab)>
+{
+    let mut virtual_device = build_virtual(device)?;
+
     // Grab the physical device to capture all events
-    if let Err(e) = device.grab() {
-        return eprintln!("Failed to grab device: {e}");
+    wait_release(device, delay);
+    let grab = match Grab::new(device, path) {
+        Ok(grab) => grab,
+        Err(e) => { eprintln!("Failed to grab device: {e}"); return None }
+    };
+    drain(device);
+
+    // Keys still held once grabbed are pressed on the virtual device too, so their releases
+    // reach the compositor as a balanced pair. Read after the drain: a key let go before it
+    // lost its release there, pressing it would leave it stuck
+    let held = device.get_key_state().unwrap_or_default();
+    let presses: Vec<_> = held.iter().map(|k| InputEvent::new(EventType::KEY.0, k.code(), 1)).collect();
+    if !presses.is_empty() && let Err(e) = virtual_device.emit(&presses) {
+        eprintln!("Failed to emit events: {e}");
     }
+    Some((virtual_device, held, grab))
+}
+
+fn read_keys(config: &Config, kbs: PathBuf, delay: u64, user: User)
+{
+    let kc = &config.bindings;
+    let mut state = vec![(0, 0); kc.len()];
+    let mut tick = 1;
+    let init = 320_u64.div_ceil(delay);
+
+    let mut device = match helper::open_device(&kbs) {
+        Ok(dev) => dev,
+        Err(_) => return, // Device no longer exists, exit thread
+    };
+
+    // In listen-only mode nothing is grabbed, the events are only observed.
+    // Otherwise `forwarded` holds the key-downs that reached the virtual device,
+    // a key-up is only forwarded for these
+    let (mut virtual_device, mut forwarded, mut grab) = if config.listen_only {
+        (None, AttributeSet::new(), None)
+    } else {
+        match take_over(&mut device, &kbs, delay) {
+            Some((virtual_device, held, grab)) => (Some(virtual_device), held, Some(grab)),
+            None => return,
+        }
+    };
+    systemd::set_up(&kbs);
+    let mut escape_since = None;
+    let mut failures = 0;
 
     loop {
-        // Collect events
-        let events: Vec<_> = match device.fetch_events() {
-            Ok(events_iter) => events_iter.collect(),
-            Err(_) => break, // Device no longer exists
+        // The config was reloaded, the supervisor starts over with the new one
+        if !std::ptr::eq(config, Arc::as_ptr(&settings())) { break; }
+        systemd::beat(&kbs);
+
+        // Collect events, waking up regularly so a held escape combo is noticed without them
+        let events: Vec<_> = if readable(&device, WAKE) {
+            match device.fetch_events() {
+                Ok(events_iter) => events_iter.collect(),
+                Err(_) => break, // Device no longer exists
+            }
+        } else {
+            Vec::new()
         };
-        
+
         let mut events_to_forward = Vec::new();
         let current_state = match device.get_key_state() {
             Ok(state) => state,
             Err(_) => break, // Device no longer exists
         };
-        
-        // Check if any shortcut is currently active
+
+        // The escape combo comes before any binding or forwarding and can't be overridden.
+        // Exiting closes every device, so even keyboards of a hung worker are released.
+        // It exits with 0: systemd's Restart=on-failure would grab them all again
+        if config.escape.iter().all(|k| current_state.contains(k)) {
+            let since = *escape_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= config.escape_hold {
+                release_all();
+                warn("Escape", "Escape combo held, every keyboard is released");
+                exit(0);
+            }
+        } else {
+            escape_since = None;
+        }
+        if events.is_empty() { continue; }
+
+        // Check if any suppressing shortcut is currently active
         let mut active_shortcut = false;
-        for (combo, _) in kc {
+        for binding in kc.iter().filter(|b| !b.passthrough) {
             // Check if all keys in combo are pressed
-            if combo.iter().all(|k| current_state.contains(k)) {
+            if binding.keys.iter().all(|k| current_state.contains(k)) {
                 active_shortcut = true;
                 break;
             }
         }
-        
+
         for event in events {
-            if event.event_type() == EventType::KEY {
-                let key_code = KeyCode::new(event.code());
-                
-                // Check if this key is part of any shortcut
-                let mut is_shortcut_key = false;
-                for (combo, _) in kc {
-                    if combo.contains(key_code) {
-                        is_shortcut_key = true;
-                        break;
-                    }
-                }
-                
-                if is_shortcut_key && active_shortcut {
-                    for (i, (combo, cmd)) in kc.iter().enumerate() {
-                        // Check if all keys in combo are pressed
-                        let pressed = combo.iter().all(|k| current_state.contains(k));
-                        let (since, last) = &mut state[i];
-                        if pressed {
-                            if *since == 0 {
-                                *since = tick;
-                                *last = tick;
-                            } else if tick - *since >= init && tick > *last {
-                                *last = tick;
-                            }
-                            run(cmd, &user);
-                        } else {
-                            *since = 0;
+            if event.event_type() != EventType::KEY {
+                events_to_forward.push(event);
+                continue;
+            }
+            let key_code = KeyCode::new(event.code());
+
+            // `pindc bind` is waiting for a combo, it gets the keys instead of the bindings.
+            // Keys pressed before are forwarded as usual, so their release isn't stuck
+            if !forwarded.contains(key_code) && capture::feed(key_code, event.value()) { continue; }
+
+            // Run every binding this press or repeat belongs to
+            if event.value() != 0 {
+                for (i, binding) in kc.iter().enumerate().filter(|(_, b)| b.keys.contains(key_code)) {
+                    // Check if all keys in combo are pressed
+                    let pressed = binding.keys.iter().all(|k| current_state.contains(k));
+                    let (since, last) = &mut state[i];
+                    if pressed {
+                        if *since == 0 {
+                            *since = tick;
+                            *last = tick;
+                        } else if tick - *since >= init && tick > *last {
+                            *last = tick;
                         }
+                        run(i, binding, &user);
+                    } else {
+                        *since = 0;
                     }
-                } else {
+                }
+            }
+
+            // Check if this key is part of any suppressing shortcut, passthrough ones leave it alone
+            let mut is_shortcut_key = false;
+            for binding in kc.iter().filter(|b| !b.passthrough) {
+                if binding.keys.contains(key_code) {
+                    is_shortcut_key = true;
+                    break;
+                }
+            }
+
+            match event.value() {
+                // Releases and repeats follow their press: they only go out if the press did
+                _ if forwarded.contains(key_code) => {
+                    if event.value() == 0 { forwarded.remove(key_code); }
                     events_to_forward.push(event);
                 }
-            } else {
-                events_to_forward.push(event);
+                0 => {} // Release of a swallowed key
+                _ if is_shortcut_key && active_shortcut => {} // Swallowed by the shortcut
+                1 => {
+                    forwarded.insert(key_code);
+                    events_to_forward.push(event);
+                }
+                _ => {} // Repeat of a swallowed key
             }
         }
-        if !events_to_forward.is_empty() && let Err(e) = virtual_device.emit(&events_to_forward) {
-                eprintln!("Failed to emit events: {e}");
+        if let Some(virtual_device) = virtual_device.as_mut() && !events_to_forward.is_empty() {
+            match virtual_device.emit(&events_to_forward) {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    eprintln!("Failed to emit events: {e}");
+                    // A uinput device that is gone won't come back, don't wait for more failures
+                    failures = if e.raw_os_error() == Some(libc::ENODEV) { FAILS } else { failures + 1 };
+                }
+            }
+        }
+
+        // Forwarding keeps failing, so the keyboard is swallowed: rebuild the virtual device,
+        // or give the keyboard back to the compositor and only watch it for bindings
+        if failures >= FAILS {
+            failures = 0;
+            forwarded = AttributeSet::new();
+            virtual_device = build_virtual(&device);
+            if virtual_device.is_some() {
+                warn("Forward", &format!("Rebuilt the virtual device for {}", kbs.display()));
+            } else if let Some(grab) = grab.take() {
+                if let Err(e) = grab.release(&mut device) { eprintln!("Failed to ungrab device: {e}"); }
+                warn("Forward", &format!("Released {}, bindings can't suppress its keys anymore", kbs.display()));
+            }
         }
 
-        thread::sleep(std::time::Duration::from_millis(delay));
+        thread::sleep(Duration::from_millis(delay));
         tick = tick.wrapping_add(1);
     }
 }
 
+// `--check`: parse the config like the engine would, as the user, and exit 1 on problems
+fn check_config(args: &Args, user: &User) -> !
+{
+    if unsafe { libc::geteuid() } == 0 && let Err(e) = user.become_user() {
+        error("Check", &format!("Failed to drop privileges: {e}"));
+    }
+    if let Some(env_file) = &args.env_file && let Err(e) = environ::load(env_file, user.uid) {
+        error("Environment", &e);
+    }
+    let problems = check(&get_config(args, user));
+    for problem in &problems { warn("Config", problem); }
+    exit(i32::from(!problems.is_empty()))
+}
+
+// Print the running and recently finished commands
+fn report()
+{
+    for record in procs::running() {
+        eprintln!("running   pid {:<7} {:>8.1?}  {} => {}", record.pid, record.duration, record.binding, record.command);
+    }
+    for record in procs::finished() {
+        let status = record.status.map_or("unknown".into(), |status| status.to_string());
+        let ago = record.started.elapsed().unwrap_or_default().as_secs();
+        eprintln!("finished  pid {:<7} {:>8.1?}  {} => {} ({status}, started {ago}s ago)", record.pid, record.duration, record.binding, record.command);
+    }
+}
+
+// Stop the running commands and hand the keyboards back before exiting
+fn shutdown() -> !
+{
+    systemd::notify("STOPPING=1");
+    procs::kill_all(libc::SIGTERM);
+    release_all();
+    exit(0)
+}
+
+// Block the signals pindd handles in every thread and wait for them on a dedicated one.
+// SIGTERM and SIGINT stop the running commands and exit, SIGUSR1 reports them
+fn signals()
+{
+    let set = unsafe {
+        let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(set.as_mut_ptr());
+        for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGUSR1] { libc::sigaddset(set.as_mut_ptr(), signal); }
+        libc::pthread_sigmask(libc::SIG_BLOCK, set.as_ptr(), std::ptr::null_mut());
+        set.assume_init()
+    };
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 { continue; }
+        match signal {
+            libc::SIGUSR1 => report(),
+            _ => shutdown(),
+        }
+    });
+}
+
+// Answer pindc on the control socket, one request per connection
+fn control(listener: UnixListener, user: User, listen_only: bool, pid: u32)
+{
+    let started = Instant::now();
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else { continue };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+        let mut request = String::new();
+        if BufReader::new(&stream).read_line(&mut request).is_err() { continue; }
+
+        let answer = match request.trim() {
+            "status" => {
+                let config = settings();
+                let uptime = started.elapsed().as_secs();
+                let mut status = format!("pid       {pid}\nuptime    {}:{:02}:{:02}\nconfig    {}\nbindings  {}\nrunning   {}\n",
+                    uptime / 3600, uptime / 60 % 60, uptime % 60, config.path.display(), config.bindings.len(), procs::running().len());
+                let grabbed = grabbed();
+                if config.listen_only { status.push_str("grabbed   none, listen-only\n"); }
+                for (i, path) in grabbed.iter().enumerate() {
+                    status.push_str(&format!("{:10}{}\n", if i == 0 { "grabbed" } else { "" }, path.display()));
+                }
+                status
+            }
+            "reload" => match reload(&user, listen_only) {
+                Ok(config) => format!("reloaded {} bindings\n", config.bindings.len()),
+                Err(e) => format!("error: {e}, the previous config stays\n"),
+            },
+            // Answered from its own thread, status and the rest don't wait for the keys
+            "capture" => {
+                thread::spawn(move || {
+                    let answer = match capture::next(control::CAPTURE).map(|keys| config::combo(&keys)) {
+                        Ok(Ok(combo)) => format!("{combo}\n"),
+                        Ok(Err(key)) => format!("error: {key:?} has no name in pindrc, it can't be bound\n"),
+                        Err(e) => format!("error: {e}\n"),
+                    };
+                    let _ = stream.write_all(answer.as_bytes());
+                });
+                continue;
+            }
+            "stop" => {
+                let _ = stream.write_all(b"stopping\n");
+                drop(stream);
+                shutdown();
+            }
+            request => format!("error: unknown request {request}\n"),
+        };
+        let _ = stream.write_all(answer.as_bytes());
+    }
+}
+
+// Keep a worker running for the keyboard, restarting it with backoff when it crashes
+// or fails to set up, until the keyboard is unplugged
+fn supervise(keyboard: PathBuf, user: User)
+{
+    let mut backoff = BACKOFF.0;
+    loop {
+        let config = settings();
+        let started = Instant::now();
+        let (config_ref, keyboard_ref, user_ref) = (Arc::clone(&config), keyboard.clone(), user.clone());
+        let worker = thread::Builder::new()
+            .name(WORKER.into())
+            .spawn(move || read_keys(&config_ref, keyboard_ref, DELAY, user_ref));
+        match worker.map(|worker| worker.join()) {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => warn("Thread", &format!("Keyboard thread for {} panicked", keyboard.display())),
+            Err(e) => warn("Thread", &e.to_string()),
+        }
+        systemd::forget(&keyboard);
+        if !keyboard.exists() { return; }
+        if !Arc::ptr_eq(&config, &settings()) { continue; } // Reloaded, no crash to back off from
+
+        // A worker that ran for a while is crashing for a new reason, start over
+        if started.elapsed() >= Duration::from_secs(BACKOFF.1) { backoff = BACKOFF.0; }
+        warn("Thread", &format!("Restarting keyboard thread for {} in {backoff}s", keyboard.display()));
+        systemd::notify(&format!("STATUS=Restarting keyboard thread for {} in {backoff}s", keyboard.display()));
+        thread::sleep(Duration::from_secs(backoff));
+        backoff = (backoff * 2).min(BACKOFF.1);
+    }
+}
+
 fn main()
 {
-    let user = args().nth(1).unwrap_or_else(|| error("USER", "Username argument required"));
-    let bindings = get_bindings();
-    if bindings.is_empty() { error("binding", "No key bindings detected"); }
+    // A panicking worker only loses its own keyboard, which it releases while unwinding,
+    // and is restarted. Anywhere else the daemon dies, so every keyboard is released first
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if thread::current().name() != Some(WORKER) { release_all(); }
+        default_hook(info);
+    }));
+
+    let args = parse_args();
+    if let Some(kind) = &args.print_unit {
+        print!("{}", systemd::unit(kind).unwrap_or_else(|| error("Argument", &format!("--print-unit takes system or user, not {kind}"))));
+        exit(0);
+    }
+    if args.list_devices {
+        devices::list(args.json);
+        exit(0);
+    }
+    if args.record {
+        record::record();
+        exit(0);
+    }
+    if args.doctor { doctor::run(&args.user, args.config.clone(), args.env_file.as_deref()); }
+    let user = user::lookup(&args.user).unwrap_or_else(|| error("USER", &format!("user {} is not exists", args.user)));
+    if args.check { check_config(&args, &user); }
+
+    // Root only takes the lock and the control socket in /run/pind and finds the keyboards,
+    // everything the user wrote (environment, config, log) is left to the engine
+    let _lock = lock::acquire(&user.name, args.replace);
     let keyboards = keyboards();
     if keyboards.is_empty() { error("Hardware", "No keyboards detected"); }
+    let listener = control::listen(&user);
+    let pid = std::process::id(); // the helper's once split, it is the one in the pidfile
+    helper::split(&user);
+
+    if let Some(env_file) = &args.env_file && let Err(e) = environ::load(env_file, user.uid) {
+        error("Environment", &e);
+    }
+    let config = get_config(&args, &user);
+    for problem in check(&config) { warn("Config", &problem); }
+    if config.bindings.is_empty() { error("binding", "No key bindings detected"); }
+    log::open(&user);
+    signals();
+    systemd::start(keyboards.len());
 
+    let (user_ref, listen_only) = (user.clone(), args.listen_only);
+    thread::spawn(move || control(listener, user_ref, listen_only, pid));
     let handles: Vec<_> = keyboards.into_iter().map(|keyboard| {
-        let bindings_ref = Arc::clone(&bindings);
         let user_ref = user.clone();
-        thread::spawn(move || read_keys(&bindings_ref, keyboard, DELAY, user_ref))
+        thread::spawn(move || supervise(keyboard, user_ref))
     }).collect();
-    
+
     for handle in handles {
-        handle.join().unwrap_or_else(|_| error("Thread", "Keyboard thread panicked"));
+        let _ = handle.join();
     }
 }
@@ -0,0 +1,227 @@
+use pind::{config::{self, Config, check, key_to_keycode, load_config}, control, error, log, user::{self, User}, warn};
+use std::{env::{args, current_exe, var, var_os, vars_os}, fs::{self, File, OpenOptions}, io::{self, ErrorKind, Read, Seek, SeekFrom, Write}, os::unix::{ffi::OsStrExt, fs::{OpenOptionsExt, PermissionsExt}, process::CommandExt}, path::{Path, PathBuf}, process::{Command, exit}, thread, time::Duration};
+
+const ESCALATE :[&str; 3] = ["sudo", "doas", "run0"]; // tried in order when `start` isn't told which
+const FOLLOW   :u64 = 500; // 500ms between looks for new lines with `logs -f`
+
+const USAGE: &str = "\
+usage: pindc [command]
+
+  start [sudo|doas|run0]    start pindd with this environment, as root through the given tool
+  restart [sudo|doas|run0]  stop the running pindd and start a new one (the default)
+  stop                      stop pindd and the commands it started
+  status                    show pid, uptime, config, bindings and grabbed keyboards
+  reload                    have pindd read its config again, it keeps the old one on errors
+  logs [-f]                 print the command log, -f keeps printing new lines
+  check [--config PATH]     parse the config like pindd does and report problems
+  env                       refresh the environment given to pindd
+  bind                      press a combo, type a command and add the binding to the config";
+
+fn main()
+{
+    let mut args = args().skip(1);
+    let command = args.next().unwrap_or_else(|| "restart".into());
+    let name = var("USER").unwrap_or_else(|_| error("USER", "$USER is not set"));
+    let user = user::lookup(&name).unwrap_or_else(|| error("USER", &format!("user {name} is not exists")));
+
+    match command.as_str() {
+        "start"   => start(&user, args.next(), false),
+        "restart" => start(&user, args.next(), true),
+        // `pindc sudo`, as the script did
+        tool if ESCALATE.contains(&tool) => start(&user, Some(command), true),
+        "stop"    => ask(&user, "stop"),
+        "status"  => ask(&user, "status"),
+        // pindd keeps its config when the new one doesn't load, and answers why
+        "reload"  => ask(&user, "reload"),
+        "logs"    => logs(&user, args.next().as_deref() == Some("-f")),
+        "check"   => {
+            let explicit = match (args.next().as_deref(), args.next()) {
+                (None, _) => None,
+                (Some("--config"), Some(path)) => Some(PathBuf::from(path)),
+                _ => error("Argument", "check only takes --config PATH"),
+            };
+            let problems = check(&load(&config::resolve(explicit, &user), &user));
+            for problem in &problems { warn("Config", problem); }
+            exit(i32::from(!problems.is_empty()));
+        }
+        "env"     => { save_env(); }
+        "bind"    => bind(&user),
+        "help" | "-h" | "--help" => println!("{USAGE}"),
+        _ => { eprintln!("{USAGE}"); exit(2) }
+    }
+}
+
+// Start pindd as root for the user, in the foreground like the script did.
+// The environment goes through a file only the user can write, sudo and friends clear it otherwise
+fn start(user: &User, tool: Option<String>, replace: bool) -> !
+{
+    if !replace && control::request(&user.name, "status").is_ok() {
+        error("Start", "pindd is already running, use `pindc restart`");
+    }
+    let env_file = save_env();
+
+    // The pindd installed with this pindc, secure_path of sudo might not include its directory
+    let pindd = current_exe().ok().map(|exe| exe.with_file_name("pindd")).filter(|pindd| pindd.exists())
+        .unwrap_or_else(|| PathBuf::from("pindd"));
+    let mut command = if unsafe { libc::geteuid() } == 0 {
+        Command::new(&pindd)
+    } else {
+        let tool = tool.or_else(|| ESCALATE.iter().find(|tool| in_path(tool)).map(|tool| tool.to_string()))
+            .unwrap_or_else(|| error("Start", &format!("none of {} is installed, can't run pindd as root", ESCALATE.join(", "))));
+        let mut command = Command::new(tool);
+        command.arg(&pindd);
+        command
+    };
+    if replace { command.arg("--replace"); }
+    command.arg("--env-file").arg(&env_file).arg(&user.name);
+
+    let e = command.exec();
+    error("Start", &format!("Failed to run {}: {e}", command.get_program().to_string_lossy()))
+}
+
+fn in_path(program: &str) -> bool
+{
+    var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+// NUL separated like `env -0`, to $XDG_RUNTIME_DIR/pind/environ, which pindd reads again
+// whenever it is replaced. Written aside and renamed, so pindd never reads half of it
+fn save_env() -> PathBuf
+{
+    let dir = var_os("XDG_RUNTIME_DIR").map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(var_os("HOME").unwrap_or_default()).join(".cache"))
+        .join("pind");
+    let (path, new) = (dir.join("environ"), dir.join("environ.new"));
+
+    let mut content = Vec::new();
+    for (name, value) in vars_os() {
+        content.extend_from_slice(name.as_bytes());
+        content.push(b'=');
+        content.extend_from_slice(value.as_bytes());
+        content.push(0);
+    }
+    let written = fs::create_dir_all(&dir)
+        .and_then(|_| fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)))
+        .and_then(|_| OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&new))
+        .and_then(|mut file| file.write_all(&content))
+        .and_then(|_| fs::rename(&new, &path));
+    if let Err(e) = written { error("Environment", &format!("Failed to write {}: {e}", path.display())); }
+    path
+}
+
+// Send a request to the running pindd and print its answer
+fn ask(user: &User, request: &str)
+{
+    match control::request(&user.name, request) {
+        Ok(answer) if answer.is_empty() => error("pindd", "closed the connection without answering, see its output"),
+        Ok(answer) => {
+            print!("{answer}");
+            if answer.starts_with("error:") { exit(1); }
+        }
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            error("pindd", &format!("not running for {}", user.name))
+        }
+        Err(e) => error("pindd", &format!("{}: {e}", control::socket(&user.name).display())),
+    }
+}
+
+// Have pindd capture a combo, it holds the keyboards, then append a binding for it to the
+// config pindd loaded and reload. The line is written the way the parser reads it, and taken
+// back out if it doesn't load
+fn bind(user: &User)
+{
+    let unreachable = |e: io::Error| -> ! {
+        if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) {
+            error("pindd", &format!("not running for {}, it captures the combo: start it with `pindc start`", user.name))
+        }
+        error("pindd", &format!("{}: {e}", control::socket(&user.name).display()))
+    };
+    // pindd may have been given --config, its status says which one it reads
+    let status = control::request(&user.name, "status").unwrap_or_else(|e| unreachable(e));
+    let path = status.lines().find_map(|line| line.strip_prefix("config")).map(|path| PathBuf::from(path.trim()))
+        .unwrap_or_else(|| error("pindd", "its status doesn't say which config it reads"));
+    let config = load(&path, user);
+
+    println!("Press the combo to bind, pindd keeps it from the applications ({}s)", control::CAPTURE.as_secs());
+    let combo = control::capture(&user.name).unwrap_or_else(|e| unreachable(e)).trim().to_string();
+    if combo.is_empty() { error("pindd", "closed the connection without answering, see its output"); }
+    if let Some(e) = combo.strip_prefix("error:") { error("Bind", e.trim()); }
+    println!("combo     {combo}");
+
+    // A binding runs whenever all of its keys are held, so one with fewer keys runs on the new
+    // combo too, and the new one runs on a binding with more keys
+    let keys = key_to_keycode(&combo).unwrap_or_else(|e| error("Bind", &e));
+    let mut conflicts = 0;
+    for binding in &config.bindings {
+        let (within, around) = (binding.keys.iter().all(|k| keys.contains(k)), keys.iter().all(|k| binding.keys.contains(k)));
+        let why = match (within, around) {
+            (true, true)  => "same keys",
+            (true, false) => "runs on the new combo too",
+            (false, true) => "the new binding runs on it too",
+            (false, false) => continue,
+        };
+        conflicts += 1;
+        println!("conflict  {} => {} ({why})", binding.combo, binding.command);
+    }
+    if conflicts > 0 && !prompt("They would run together, add it anyway? [y/N] ").eq_ignore_ascii_case("y") {
+        exit(1);
+    }
+    let command = prompt("Command, options like @single first: ");
+    if command.is_empty() { error("Bind", "no command given, the config is left as it was"); }
+
+    let path = &config.path;
+    let before = fs::read_to_string(path).unwrap_or_else(|e| error("Config", &format!("{}: {e}", path.display())));
+    let line = format!("{combo} => {command}");
+    let separator = if before.is_empty() || before.ends_with('\n') { "" } else { "\n" };
+    let appended = OpenOptions::new().append(true).open(path).and_then(|mut file| file.write_all(format!("{separator}{line}\n").as_bytes()));
+    if let Err(e) = appended { error("Config", &format!("Failed to write {}: {e}", path.display())); }
+    let config = match load_config(path, user) {
+        Ok(config) => config,
+        Err(e) => {
+            if let Err(e) = fs::write(path, &before) { error("Config", &format!("Failed to restore {}: {e}", path.display())); }
+            error("Bind", &format!("the binding doesn't load, {e}. The config is left as it was"));
+        }
+    };
+    println!("added     {line}");
+
+    for problem in check(&config) { warn("Config", &problem); }
+    ask(user, "reload");
+}
+
+fn load(path: &Path, user: &User) -> Config
+{
+    load_config(path, user).unwrap_or_else(|e| error("Config", &e))
+}
+
+// One line typed by the user, trimmed
+fn prompt(question: &str) -> String
+{
+    print!("{question}");
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if let Err(e) = io::stdin().read_line(&mut answer) { error("Input", &e.to_string()); }
+    answer.trim().to_string()
+}
+
+// Print the log, and with `follow` keep printing what is appended, across rotations
+fn logs(user: &User, follow: bool)
+{
+    let path = log::path(user);
+    let mut file = File::open(&path).unwrap_or_else(|e| error("Log", &format!("{}: {e}", path.display())));
+    let mut position = 0;
+    loop {
+        let mut new = Vec::new();
+        if file.seek(SeekFrom::Start(position)).and_then(|_| file.read_to_end(&mut new)).is_ok() {
+            position += new.len() as u64;
+            let _ = io::stdout().write_all(&new);
+        }
+        if !follow { return; }
+        thread::sleep(Duration::from_millis(FOLLOW));
+
+        // Rotated: the path leads to a new, smaller file
+        if fs::metadata(&path).is_ok_and(|meta| meta.len() < position) && let Ok(rotated) = File::open(&path) {
+            file = rotated;
+            position = 0;
+        }
+    }
+}
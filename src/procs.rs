@@ -0,0 +1,194 @@
+use crate::log;
+use std::{collections::VecDeque, process::{Child, ExitStatus}, sync::{Mutex, Once, PoisonError}, thread, time::{Duration, Instant, SystemTime}};
+
+const REAP    :u64   = 100; // 100ms between checks for finished commands
+const HISTORY :usize = 32;  // finished commands kept around
+const GRACE   :u64   = 2;   // seconds between SIGTERM and SIGKILL for a command past its timeout
+
+#[derive(Clone)]
+pub struct Record
+{
+    pub pid: u32,
+    pub binding: String, // combo as written in pindrc
+    pub command: String,
+    pub started: SystemTime,
+    pub duration: Duration,
+    pub status: Option<ExitStatus>, // None while running
+    pub timed_out: bool,
+}
+
+struct Running
+{
+    id: usize, // index of the binding
+    child: Child,
+    since: Instant,
+    timeout: Option<Duration>,
+    terminated: Option<Instant>, // SIGTERM sent for the timeout
+    killed: bool, // SIGKILL sent after it
+    record: Record,
+}
+
+static RUNNING: Mutex<Vec<Running>> = Mutex::new(Vec::new());
+static FINISHED: Mutex<VecDeque<Record>> = Mutex::new(VecDeque::new());
+static QUEUED: Mutex<Vec<(usize, Launch)>> = Mutex::new(Vec::new());
+static LINGERING: Mutex<Vec<(i32, Instant)>> = Mutex::new(Vec::new()); // groups of terminated commands whose leader exited, and when they get SIGKILL
+static REAPER: Once = Once::new();
+
+pub type Launch = Box<dyn FnOnce() + Send>;
+
+// Keep track of a spawned command until it exits, its process group is the one `kill` signals
+pub fn track(child: Child, id: usize, binding: &str, command: &str, timeout: Option<Duration>)
+{
+    REAPER.call_once(|| { thread::spawn(reap); });
+    let record = Record {
+        pid: child.id(),
+        binding: binding.to_string(),
+        command: command.to_string(),
+        started: SystemTime::now(),
+        duration: Duration::ZERO,
+        status: None,
+        timed_out: false,
+    };
+    RUNNING.lock().unwrap_or_else(PoisonError::into_inner).push(Running { id, child, since: Instant::now(), timeout, terminated: None, killed: false, record });
+}
+
+// Past its timeout a command gets SIGTERM, and SIGKILL if it is still there after the grace period
+fn expire(running: &mut Running)
+{
+    let group = -(running.record.pid as i32);
+    match running.terminated {
+        None if running.timeout.is_some_and(|timeout| running.since.elapsed() >= timeout) => {
+            log::write(&running.record.binding, &format!("`{}` timed out after {:.1?}, terminating it", running.record.command, running.since.elapsed()));
+            unsafe { libc::kill(group, libc::SIGTERM) };
+            running.terminated = Some(Instant::now());
+            running.record.timed_out = true;
+        }
+        Some(terminated) if terminated.elapsed() >= Duration::from_secs(GRACE) => {
+            log::write(&running.record.binding, &format!("`{}` ignored SIGTERM, killing it", running.record.command));
+            unsafe { libc::kill(group, libc::SIGKILL) };
+            running.terminated = Some(Instant::now());
+            running.killed = true;
+        }
+        _ => {}
+    }
+}
+
+// The leader exiting on SIGTERM doesn't mean the rest of its group did,
+// what is left of it still gets SIGKILL once the grace period is over
+fn linger(running: &Running)
+{
+    if let Some(terminated) = running.terminated && !running.killed {
+        let group = -(running.record.pid as i32);
+        LINGERING.lock().unwrap_or_else(PoisonError::into_inner).push((group, terminated + Duration::from_secs(GRACE)));
+    }
+}
+
+// Wait for commands without blocking on any of them, nothing is left a zombie
+fn reap()
+{
+    loop {
+        let mut done = Vec::new();
+        RUNNING.lock().unwrap_or_else(PoisonError::into_inner).retain_mut(|running| {
+            match running.child.try_wait() {
+                Ok(None) => { expire(running); true }
+                status => {
+                    linger(running);
+                    running.record.status = status.ok().flatten();
+                    running.record.duration = running.since.elapsed();
+                    done.push((running.id, running.record.clone()));
+                    false
+                }
+            }
+        });
+
+        let mut finished = FINISHED.lock().unwrap_or_else(PoisonError::into_inner);
+        for (_, record) in &done {
+            let status = record.status.map_or("unknown status".into(), |status| status.to_string());
+            let timed_out = if record.timed_out { ", timed out" } else { "" };
+            log::write(&record.binding, &format!("`{}` finished with {status} after {:.1?}{timed_out}", record.command, record.duration));
+            if finished.len() == HISTORY { finished.pop_front(); }
+            finished.push_back(record.clone());
+        }
+        drop(finished);
+
+        LINGERING.lock().unwrap_or_else(PoisonError::into_inner).retain(|&(group, deadline)| {
+            if Instant::now() < deadline { return true; }
+            unsafe { libc::kill(group, libc::SIGKILL) };
+            false
+        });
+
+        // A queued binding starts its next command once the previous one is gone. One that
+        // fails to start is skipped for the next, they would wait for an unrelated press otherwise.
+        // The queue stays locked meanwhile, so `enqueue` sees either the entry or its command
+        for (id, _) in done {
+            let mut queued = QUEUED.lock().unwrap_or_else(PoisonError::into_inner);
+            while !is_running(id) && let Some(i) = queued.iter().position(|(queued_id, _)| *queued_id == id) {
+                (queued.remove(i).1)();
+            }
+        }
+        thread::sleep(Duration::from_millis(REAP));
+    }
+}
+
+pub fn running() -> Vec<Record>
+{
+    RUNNING.lock().unwrap_or_else(PoisonError::into_inner).iter().map(|running| {
+        Record { duration: running.since.elapsed(), ..running.record.clone() }
+    })
+    .collect()
+}
+
+pub fn finished() -> Vec<Record>
+{
+    FINISHED.lock().unwrap_or_else(PoisonError::into_inner).iter().cloned().collect()
+}
+
+pub fn is_running(id: usize) -> bool
+{
+    RUNNING.lock().unwrap_or_else(PoisonError::into_inner).iter().any(|running| running.id == id)
+}
+
+// Run `launch` after the binding's running commands, and the ones queued before it.
+// Nothing running nor queued, it is given back to be run right away
+pub fn enqueue(id: usize, launch: Launch) -> Result<(), Launch>
+{
+    let mut queued = QUEUED.lock().unwrap_or_else(PoisonError::into_inner);
+    if !is_running(id) && !queued.iter().any(|(queued_id, _)| *queued_id == id) { return Err(launch); }
+    queued.push((id, launch));
+    Ok(())
+}
+
+// The bindings were reloaded, `ids` has the new id of every old binding that didn't change.
+// Their running commands follow them, those of removed or changed bindings are terminated like
+// past a timeout. Queued commands are dropped, they would start the old bindings
+pub fn reload(ids: &[Option<usize>])
+{
+    QUEUED.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    for running in RUNNING.lock().unwrap_or_else(PoisonError::into_inner).iter_mut() {
+        if let Some(id) = ids.get(running.id).copied().flatten() {
+            running.id = id;
+            continue;
+        }
+        running.id = usize::MAX;
+        if running.terminated.is_some() { continue; }
+        log::write(&running.record.binding, &format!("`{}` belongs to a binding that was removed or changed, terminating it", running.record.command));
+        unsafe { libc::kill(-(running.record.pid as i32), libc::SIGTERM) };
+        running.terminated = Some(Instant::now());
+    }
+}
+
+// Signal the process group of every running command of a binding
+pub fn kill(id: usize, signal: i32)
+{
+    for running in RUNNING.lock().unwrap_or_else(PoisonError::into_inner).iter().filter(|running| running.id == id) {
+        unsafe { libc::kill(-(running.record.pid as i32), signal) };
+    }
+}
+
+// Signal the whole process group of every running command, the reaper collects them
+pub fn kill_all(signal: i32)
+{
+    for running in RUNNING.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        unsafe { libc::kill(-(running.record.pid as i32), signal) };
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{config::{combo, key_name}, lock::{self, RUN}, warn};
+use evdev::{Device, EventType, KeyCode, MiscCode, enumerate};
+use std::{fs::File, os::fd::OwnedFd, path::{Path, PathBuf}, thread};
+
+// Print every key press and release with the names pindrc uses, to help writing bindings.
+// Devices are opened read-only and never grabbed. A keyboard a running pindd grabbed
+// (EVIOCGRAB) gives other readers no events at all, bound keys or not, so that is warned about
+pub fn record()
+{
+    let mut dirs = vec![PathBuf::from(RUN)];
+    if lock::run_dir() != Path::new(RUN) { dirs.push(lock::run_dir()); }
+    for (user, pid) in dirs.iter().flat_map(|dir| lock::holders(dir)) {
+        let pid = pid.map_or(String::new(), |pid| format!(" (pid {pid})"));
+        warn("Record", &format!("pindd is running for {user}{pid}, the keyboards it grabbed stay silent here: stop it with `pindc stop`"));
+    }
+
+    let paths: Vec<PathBuf> = enumerate().filter(|(_, device)| device.supported_keys().is_some()).map(|(path, _)| path).collect();
+    if paths.is_empty() { crate::error("Hardware", "No device with keys could be opened"); }
+    println!("Press keys to see their names, Ctrl+C to stop");
+
+    let handles: Vec<_> = paths.into_iter().filter_map(|path| {
+        let device = File::open(&path).map(OwnedFd::from).and_then(Device::from_fd)
+            .map_err(|e| eprintln!("Failed to open {}: {e}", path.display())).ok()?;
+        Some(thread::spawn(move || watch(device)))
+    }).collect();
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+fn watch(mut device: Device)
+{
+    let name = device.name().unwrap_or("unnamed").to_string();
+    let mut held: Vec<KeyCode> = Vec::new(); // in the order they were pressed
+    let mut scancode = None;
+    loop {
+        let Ok(events) = device.fetch_events() else { return }; // Unplugged
+        for event in events {
+            // The scancode comes right before the key it belongs to
+            if event.event_type() == EventType::MISC && event.code() == MiscCode::MSC_SCAN.0 {
+                scancode = Some(event.value() as u32);
+                continue;
+            }
+            if event.event_type() != EventType::KEY || event.value() == 2 { continue; }
+
+            let key = KeyCode::new(event.code());
+            let action = if event.value() == 1 {
+                if !held.contains(&key) { held.push(key); }
+                "press  "
+            } else {
+                held.retain(|&k| k != key);
+                "release"
+            };
+            let scancode = scancode.take().map_or("-".into(), |scancode| format!("{scancode:#x}"));
+            let combo = match combo(&held) {
+                Ok(combo) if combo.is_empty() => "-".into(),
+                Ok(combo) => combo,
+                Err(key) => format!("{key:?} has no name in pindrc"),
+            };
+            println!("{action}  {:<12} {:<20} scancode {scancode:<8} {name:<28} combo: {combo}",
+                key_name(key).unwrap_or("-"), format!("{key:?} ({})", key.code()));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::{env::{current_exe, var, var_os}, ffi::OsStr, os::{linux::net::SocketAddrExt, unix::{ffi::OsStrExt, net::{SocketAddr, UnixDatagram}}}, path::{Path, PathBuf}, sync::{Mutex, Once, PoisonError, atomic::{AtomicUsize, Ordering}}, thread, time::{Duration, Instant}};
+
+// pind@.service, the instance is the user: `systemctl enable --now pind@alice`.
+// Commands get the session environment once `pindc env` has written it
+const SYSTEM: &str = "\
+[Unit]
+Description=Pind hotkey daemon for %i
+After=systemd-user-sessions.service
+
+[Service]
+Type=notify
+NotifyAccess=all
+ExecStart=/bin/sh -c 'exec {pindd} --replace --env-file \"/run/user/$(id -u %i)/pind/environ\" %i'
+Restart=on-failure
+RestartSec=2
+WatchdogSec=30
+
+[Install]
+WantedBy=multi-user.target
+";
+
+// ~/.config/systemd/user/pind.service, when the user can read the keyboards and /dev/uinput
+const USER: &str = "\
+[Unit]
+Description=Pind hotkey daemon
+PartOf=graphical-session.target
+After=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=all
+ExecStart={pindd} --replace %u
+Restart=on-failure
+RestartSec=2
+WatchdogSec=30
+
+[Install]
+WantedBy=graphical-session.target
+";
+
+static READY: Once = Once::new();
+static KEYBOARDS: AtomicUsize = AtomicUsize::new(usize::MAX);
+static BEATS: Mutex<Vec<(PathBuf, Instant)>> = Mutex::new(Vec::new()); // last loop of every set up worker
+static TRIED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new()); // keyboards whose worker set up or stopped once
+
+// The unit for `kind` (system or user), running this pindd
+pub fn unit(kind: &str) -> Option<String>
+{
+    let pindd = current_exe().unwrap_or_else(|_| PathBuf::from("/usr/local/bin/pindd"));
+    let unit = match kind {
+        "system" => SYSTEM,
+        "user"   => USER,
+        _ => return None,
+    };
+    Some(unit.replace("{pindd}", &pindd.to_string_lossy()))
+}
+
+// Send a state like READY=1 to $NOTIFY_SOCKET, a path or an abstract name starting with @.
+// Not started by systemd, nothing to do
+pub fn notify(state: &str)
+{
+    let Some(path) = var_os("NOTIFY_SOCKET") else { return };
+    let path = path.as_bytes();
+    let address = match path.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(Path::new(OsStr::from_bytes(path))),
+    };
+    let sent = address.and_then(|address| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address));
+    if let Err(e) = sent { eprintln!("Failed to notify systemd: {e}"); }
+}
+
+// Ready once every keyboard's worker set up or failed, and pinging the watchdog if systemd asks for it
+pub fn start(keyboards: usize)
+{
+    KEYBOARDS.store(keyboards, Ordering::Relaxed);
+    let interval = var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse().ok()).map(Duration::from_micros);
+    if let Some(interval) = interval.filter(|interval| !interval.is_zero()) {
+        thread::spawn(move || watchdog(interval));
+    }
+}
+
+// A worker grabbed its keyboard and created its virtual device, or watches it in listen-only mode
+pub fn set_up(keyboard: &Path)
+{
+    beat(keyboard);
+    tried(keyboard);
+}
+
+// A keyboard that keeps failing (another grabber, EBUSY) doesn't hold the others back,
+// systemd would kill pindd once TimeoutStartSec is over
+fn tried(keyboard: &Path)
+{
+    let mut tried = TRIED.lock().unwrap_or_else(PoisonError::into_inner);
+    if !tried.iter().any(|path| path == keyboard) { tried.push(keyboard.to_path_buf()); }
+    if tried.len() >= KEYBOARDS.load(Ordering::Relaxed) {
+        let set_up = BEATS.lock().unwrap_or_else(PoisonError::into_inner).len();
+        READY.call_once(|| notify(&format!("READY=1\nSTATUS=Listening on {set_up} keyboards")));
+    }
+}
+
+pub fn beat(keyboard: &Path)
+{
+    let mut beats = BEATS.lock().unwrap_or_else(PoisonError::into_inner);
+    match beats.iter_mut().find(|(path, _)| path == keyboard) {
+        Some((_, last)) => *last = Instant::now(),
+        None => beats.push((keyboard.to_path_buf(), Instant::now())),
+    }
+}
+
+// The worker stopped, it doesn't hold the watchdog back until it runs again
+pub fn forget(keyboard: &Path)
+{
+    BEATS.lock().unwrap_or_else(PoisonError::into_inner).retain(|(path, _)| path != keyboard);
+    tried(keyboard);
+}
+
+// Only pinged while every running worker went through its loop lately,
+// so a hung worker gets pindd restarted
+fn watchdog(interval: Duration)
+{
+    loop {
+        thread::sleep(interval / 2);
+        let alive = BEATS.lock().unwrap_or_else(PoisonError::into_inner).iter().all(|(_, last)| last.elapsed() < interval);
+        if alive { notify("WATCHDOG=1"); }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::notify;
+    use std::{env::{set_var, temp_dir}, fs, os::{linux::net::SocketAddrExt, unix::net::{SocketAddr, UnixDatagram}}, process, time::Duration};
+
+    fn received(socket: &UnixDatagram) -> String
+    {
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buffer = [0; 64];
+        let len = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..len]).into_owned()
+    }
+
+    // Both addresses in one test, NOTIFY_SOCKET belongs to the whole process
+    #[test]
+    fn notify_path_and_abstract()
+    {
+        let path = temp_dir().join(format!("pind-notify-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        unsafe { set_var("NOTIFY_SOCKET", &path) };
+        notify("READY=1\nSTATUS=Listening on 2 keyboards");
+        assert_eq!(received(&socket), "READY=1\nSTATUS=Listening on 2 keyboards");
+        fs::remove_file(&path).unwrap();
+
+        let name = format!("pind-notify-{}", process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap();
+        unsafe { set_var("NOTIFY_SOCKET", format!("@{name}")) };
+        notify("WATCHDOG=1");
+        assert_eq!(received(&socket), "WATCHDOG=1");
+    }
+}
@@ -0,0 +1,128 @@
+use crate::environ;
+use std::{ffi::{CStr, CString, OsStr}, io, mem::MaybeUninit, os::unix::{ffi::OsStrExt, process::CommandExt}, path::PathBuf, process::Command, ptr};
+
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// A passwd entry
+#[derive(Clone)]
+pub struct User
+{
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    pub shell: PathBuf,
+}
+
+fn path(field: *const libc::c_char) -> PathBuf
+{
+    PathBuf::from(OsStr::from_bytes(unsafe { CStr::from_ptr(field) }.to_bytes()))
+}
+
+pub fn lookup(name: &str) -> Option<User>
+{
+    let name = CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut entry = MaybeUninit::<libc::passwd>::uninit();
+    let mut found = ptr::null_mut();
+    loop {
+        let e = unsafe { libc::getpwnam_r(name.as_ptr(), entry.as_mut_ptr(), buffer.as_mut_ptr(), buffer.len(), &mut found) };
+        if e != libc::ERANGE { break; }
+        buffer.resize(buffer.len() * 2, 0);
+    }
+    if found.is_null() { return None; }
+
+    let entry = unsafe { entry.assume_init() };
+    Some(User {
+        name: name.to_string_lossy().into_owned(),
+        uid: entry.pw_uid,
+        gid: entry.pw_gid,
+        home: path(entry.pw_dir),
+        shell: Some(path(entry.pw_shell)).filter(|shell| !shell.as_os_str().is_empty()).unwrap_or_else(|| "/bin/sh".into()),
+    })
+}
+
+impl User
+{
+    // Supplementary groups from /etc/group, what initgroups would set
+    pub fn groups(&self) -> Vec<libc::gid_t>
+    {
+        let Ok(name) = CString::new(self.name.as_str()) else { return vec![self.gid] };
+        let mut groups = vec![0; 64];
+        loop {
+            let mut count = groups.len() as libc::c_int;
+            if unsafe { libc::getgrouplist(name.as_ptr(), self.gid, groups.as_mut_ptr(), &mut count) } >= 0 {
+                groups.truncate(count as usize);
+                return groups;
+            }
+            groups.resize((count as usize).max(groups.len() * 2), 0);
+        }
+    }
+
+    // A command that runs as the user in a session of its own, from their home,
+    // with the user's environment minus what only made sense for root
+    pub fn command(&self, program: impl AsRef<OsStr>) -> Command
+    {
+        let mut command = self.login(program);
+        command.envs(environ::vars().into_iter().filter(|(name, _)| {
+            let name = name.as_bytes();
+            !name.starts_with(b"SUDO_") && !name.starts_with(b"DOAS_")
+                && !matches!(name, b"MAIL" | b"PWD" | b"OLDPWD" | b"NOTIFY_SOCKET" | b"WATCHDOG_USEC" | b"WATCHDOG_PID" | b"HOME" | b"USER" | b"LOGNAME" | b"SHELL")
+        }));
+        if environ::var("PATH").is_none() { command.env("PATH", PATH); }
+        command
+    }
+
+    // The same for someone else than the daemon's user (`@as`). The session environment is
+    // written by the daemon's user, LD_PRELOAD, PATH and the like would run their code as
+    // this user, so the command only gets what passwd says and the fixed PATH
+    pub fn command_as(&self, program: impl AsRef<OsStr>) -> Command
+    {
+        let mut command = self.login(program);
+        command.env("PATH", PATH);
+        command
+    }
+
+    fn login(&self, program: impl AsRef<OsStr>) -> Command
+    {
+        let mut command = Command::new(program);
+        command.env_clear()
+            .env("HOME", &self.home)
+            .env("USER", &self.name)
+            .env("LOGNAME", &self.name)
+            .env("SHELL", &self.shell);
+
+        let (uid, gid, groups) = (self.uid, self.gid, self.groups());
+        let home = CString::new(self.home.as_os_str().as_bytes()).unwrap_or_default();
+        let root = CString::new("/").unwrap_or_default();
+        // Runs in the forked child: only async-signal-safe calls, everything is prepared above
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setsid() < 0 { return Err(io::Error::last_os_error()); }
+                switch(uid, gid, &groups)?;
+                if libc::chdir(home.as_ptr()) < 0 { libc::chdir(root.as_ptr()); }
+                Ok(())
+            });
+        }
+        command
+    }
+
+    // Turn this process into the user for good
+    pub fn become_user(&self) -> io::Result<()>
+    {
+        switch(self.uid, self.gid, &self.groups())
+    }
+}
+
+// Groups go before the gid and the uid last, once it is dropped nothing else can change.
+// Only async-signal-safe calls, it also runs between fork and exec
+fn switch(uid: u32, gid: u32, groups: &[libc::gid_t]) -> io::Result<()>
+{
+    unsafe {
+        if libc::geteuid() == uid { return Ok(()); }
+        if libc::setgroups(groups.len(), groups.as_ptr()) < 0 { return Err(io::Error::last_os_error()); }
+        if libc::setgid(gid) < 0 { return Err(io::Error::last_os_error()); }
+        if libc::setuid(uid) < 0 { return Err(io::Error::last_os_error()); }
+    }
+    Ok(())
+}